@@ -0,0 +1,49 @@
+//! The `cache` subcommands, which deal with csm's cache directory.
+
+use crate::csmrc::Config;
+use crate::exit;
+use crate::micromamba::{cache_dir_path, csm_cache_dir};
+
+use log::{error, info};
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Subcommand {
+    /// Print the absolute path of the cache directory
+    Path(PathArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct PathArgs {
+    /// Create the directory if it doesn't exist yet
+    #[arg(long)]
+    pub create: bool,
+}
+
+pub fn run(config: Config, subcommand: Subcommand) -> u8 {
+    match subcommand {
+        Subcommand::Path(args) => {
+            let path = if args.create && !config.noop_mode {
+                csm_cache_dir(&config)
+            } else {
+                cache_dir_path(&config)
+                    .ok_or_else(|| {
+                        std::io::Error::other("could not determine the user's cache directory")
+                    })
+                    .and_then(std::path::absolute)
+            };
+            match path {
+                Ok(path) => {
+                    if args.create && config.noop_mode && !path.exists() {
+                        info!("Would create {}", path.display());
+                    }
+                    println!("{}", path.display());
+                    exit::SUCCESS
+                }
+                Err(e) => {
+                    error!("Could not determine the cache directory: {}", e);
+                    exit::FAILURE
+                }
+            }
+        }
+    }
+}
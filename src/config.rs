@@ -0,0 +1,79 @@
+//! The `config` subcommands, which deal with csm's own configuration.
+
+use crate::csmrc::{Config, csmrc_path, unknown_keys};
+use crate::exit;
+
+use log::{error, warn};
+use std::io::ErrorKind;
+
+#[derive(Clone, Debug, clap::Subcommand)]
+pub enum Subcommand {
+    /// Check that .csmrc can be parsed and its values make sense, and exit
+    /// with 2 if not
+    Check,
+    /// Print where csm reads its configuration from, in order of precedence,
+    /// and whether each of those files exists
+    Path,
+}
+
+pub fn run(config: Result<Config, std::io::Error>, subcommand: Subcommand) -> u8 {
+    match subcommand {
+        Subcommand::Check => check(config),
+        Subcommand::Path => path(),
+    }
+}
+
+fn path() -> u8 {
+    // There is only one source so far, but the output is ready for more.
+    let Some(path) = csmrc_path() else {
+        error!("Could not determine the home directory, so there is no .csmrc");
+        return exit::FAILURE;
+    };
+    let status = if path.is_file() {
+        "loaded"
+    } else {
+        "does not exist"
+    };
+    println!("{}\t{}", path.display(), status);
+    exit::SUCCESS
+}
+
+fn check(config: Result<Config, std::io::Error>) -> u8 {
+    let Some(path) = csmrc_path() else {
+        error!("Could not determine the home directory, so there is no .csmrc");
+        return exit::FAILURE;
+    };
+    // Parse errors from serde_yaml_ng say where in the file the problem is.
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}: {}", path.display(), e);
+            return exit::CONFIG;
+        }
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(csmrc_data) => {
+            for key in unknown_keys(&csmrc_data) {
+                warn!("{}: unknown key {:?} is ignored", path.display(), key);
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            println!("{} does not exist, the defaults are used", path.display());
+            return exit::SUCCESS;
+        }
+        Err(e) => {
+            error!("{}: {}", path.display(), e);
+            return exit::FAILURE;
+        }
+    }
+    match config.validate() {
+        Ok(()) => {
+            println!("{} is valid", path.display());
+            exit::SUCCESS
+        }
+        Err(e) => {
+            error!("{}: {}", path.display(), e);
+            exit::CONFIG
+        }
+    }
+}
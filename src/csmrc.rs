@@ -1,54 +1,274 @@
 //! Module for reading a user's ~/.csmrc, if it exists.
 
+use crate::util::homedir;
 use log::debug;
 use serde::Deserialize;
 use std::default::Default;
 use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
+/// csm's configuration, from .csmrc and the defaults. See [`PartialConfig`] for
+/// how they are combined.
+#[derive(Debug)]
 pub struct Config {
     /// Override the $MAMBA_ROOT_PREFIX when shelling out to micromamba.
-    #[serde(default)]
     pub mamba_root_prefix: Option<String>,
 
+    /// Where csm keeps its own files, such as its copy of micromamba.
+    pub cache_dir: Option<String>,
+
+    /// Path to a file to use as the template for ~/.mambarc, instead of the one
+    /// bundled with csm.
+    pub mambarc_template: Option<String>,
+
+    /// Whether to create ~/.mambarc if it doesn't exist. Sites which manage
+    /// their mambarc themselves can turn this off.
+    pub create_mambarc: bool,
+
+    /// Kill micromamba if a single run of it takes longer than this many
+    /// seconds.
+    pub micromamba_timeout: Option<u64>,
+
+    /// Make micromamba read only the ~/.mambarc csm manages, instead of
+    /// whichever rc files it finds.
+    pub use_managed_mambarc: bool,
+
+    /// Extra arguments to pass to micromamba every time, before those for the
+    /// particular command.
+    pub micromamba_args: Vec<String>,
+
+    /// The names of the environment file in a project directory, tried in
+    /// order.
+    pub env_file_names: Vec<String>,
+
+    /// Appended to every environment name csm works out, with a hyphen, e.g.
+    /// `${HOSTNAME}` to tell apart the environments of several hosts sharing a
+    /// root prefix. Environment variables in it are expanded.
+    pub env_name_suffix: Option<String>,
+
+    /// A command to run in an environment right after `csm env create` has
+    /// created it, split at whitespace.
+    pub post_create: Option<String>,
+
+    /// Extra environment variables to set for micromamba, from --env-file. They
+    /// can't be set in .csmrc.
+    pub extra_env: Vec<(String, String)>,
+
+    /// Use a throwaway cache directory for this run instead of the usual one,
+    /// from --ephemeral-cache. It can't be set in .csmrc.
+    pub ephemeral_cache: bool,
+
     /// If true, don't make any changes or call any commands, just print what
     /// we *would* do normally.
-    #[serde(default)]
     pub noop_mode: bool,
 }
 
+/// The settings from one source of configuration, such as .csmrc, in which
+/// anything the source doesn't set is `None`. The fields are those of
+/// [`Config`] which can be set in .csmrc.
+///
+/// Sources are layered with [`PartialConfig::merge()`], and what none of them
+/// sets comes from the defaults when converting into a [`Config`]. That way a
+/// source which sets only `cache_dir` leaves everything else as the sources
+/// below it have it.
+#[derive(Debug, Default, Deserialize)]
+pub struct PartialConfig {
+    pub mamba_root_prefix: Option<String>,
+    pub cache_dir: Option<String>,
+    pub mambarc_template: Option<String>,
+    pub create_mambarc: Option<bool>,
+    pub micromamba_timeout: Option<u64>,
+    pub use_managed_mambarc: Option<bool>,
+    pub micromamba_args: Option<Vec<String>>,
+    pub env_file_names: Option<Vec<String>>,
+    pub env_name_suffix: Option<String>,
+    pub post_create: Option<String>,
+    pub noop_mode: Option<bool>,
+}
+
+impl PartialConfig {
+    /// Layer `over` on top of `self`: each setting `over` has wins, the others
+    /// are kept from `self`. Lists are replaced as a whole, not combined.
+    pub fn merge(self, over: PartialConfig) -> PartialConfig {
+        PartialConfig {
+            mamba_root_prefix: over.mamba_root_prefix.or(self.mamba_root_prefix),
+            cache_dir: over.cache_dir.or(self.cache_dir),
+            mambarc_template: over.mambarc_template.or(self.mambarc_template),
+            create_mambarc: over.create_mambarc.or(self.create_mambarc),
+            micromamba_timeout: over.micromamba_timeout.or(self.micromamba_timeout),
+            use_managed_mambarc: over.use_managed_mambarc.or(self.use_managed_mambarc),
+            micromamba_args: over.micromamba_args.or(self.micromamba_args),
+            env_file_names: over.env_file_names.or(self.env_file_names),
+            env_name_suffix: over.env_name_suffix.or(self.env_name_suffix),
+            post_create: over.post_create.or(self.post_create),
+            noop_mode: over.noop_mode.or(self.noop_mode),
+        }
+    }
+}
+
+impl From<PartialConfig> for Config {
+    fn from(partial: PartialConfig) -> Self {
+        let default = Config::default();
+        Config {
+            mamba_root_prefix: partial.mamba_root_prefix.or(default.mamba_root_prefix),
+            cache_dir: partial.cache_dir.or(default.cache_dir),
+            mambarc_template: partial.mambarc_template.or(default.mambarc_template),
+            create_mambarc: partial.create_mambarc.unwrap_or(default.create_mambarc),
+            micromamba_timeout: partial.micromamba_timeout.or(default.micromamba_timeout),
+            use_managed_mambarc: partial
+                .use_managed_mambarc
+                .unwrap_or(default.use_managed_mambarc),
+            micromamba_args: partial.micromamba_args.unwrap_or(default.micromamba_args),
+            env_file_names: partial.env_file_names.unwrap_or(default.env_file_names),
+            env_name_suffix: partial.env_name_suffix.or(default.env_name_suffix),
+            post_create: partial.post_create.or(default.post_create),
+            noop_mode: partial.noop_mode.unwrap_or(default.noop_mode),
+            ..default
+        }
+    }
+}
+
+/// The keys which can be set in .csmrc, i.e. the fields of [`PartialConfig`].
+pub const KNOWN_KEYS: &[&str] = &[
+    "mamba_root_prefix",
+    "cache_dir",
+    "mambarc_template",
+    "create_mambarc",
+    "micromamba_timeout",
+    "use_managed_mambarc",
+    "micromamba_args",
+    "env_file_names",
+    "env_name_suffix",
+    "post_create",
+    "noop_mode",
+];
+
+/// Where the user's .csmrc is, whether or not it exists.
+pub fn csmrc_path() -> Option<PathBuf> {
+    homedir().map(|home| home.join(".csmrc"))
+}
+
+/// Return the top-level keys in the YAML `csmrc_data` which csm doesn't know
+/// about, which are ignored when parsing it.
+pub fn unknown_keys(csmrc_data: &str) -> Vec<String> {
+    let Ok(serde_yaml_ng::Value::Mapping(mapping)) = serde_yaml_ng::from_str(csmrc_data) else {
+        return vec![];
+    };
+    mapping
+        .keys()
+        .filter_map(|key| key.as_str())
+        .filter(|key| !KNOWN_KEYS.contains(key))
+        .map(String::from)
+        .collect()
+}
+
+fn default_create_mambarc() -> bool {
+    true
+}
+
+fn default_env_file_names() -> Vec<String> {
+    vec!["robotmk-env.yaml".into(), "robotmk-env.yml".into()]
+}
+
 #[allow(clippy::derivable_impls)]
 impl Default for Config {
     fn default() -> Self {
         Config {
             mamba_root_prefix: None,
+            cache_dir: None,
+            mambarc_template: None,
+            create_mambarc: default_create_mambarc(),
+            micromamba_timeout: None,
+            use_managed_mambarc: false,
+            micromamba_args: vec![],
+            env_file_names: default_env_file_names(),
+            env_name_suffix: None,
+            post_create: None,
+            extra_env: vec![],
+            ephemeral_cache: false,
             noop_mode: false,
         }
     }
 }
 
-impl Config {
-    /// Read the user's ~/.csmrc if it exists, merging with the Default instance for
-    /// Config. Return Err if a config file was found but failed to parse, otherwise
-    /// Ok with the result of merging the config file values with the Default (and
-    /// simply the Default if no config file exists).
+impl PartialConfig {
+    /// Read the settings in the user's ~/.csmrc, if it exists. Return Err if it
+    /// was found but failed to parse, and no settings at all if it wasn't
+    /// found.
     pub fn from_csmrc() -> Result<Self, std::io::Error> {
-        let Some(home) = dirs::home_dir() else {
+        let Some(csmrc_path) = csmrc_path() else {
             return Ok(Self::default());
         };
-        let csmrc_path = home.join(".csmrc");
         match std::fs::read_to_string(csmrc_path) {
             Err(e) if e.kind() == ErrorKind::NotFound => {
                 debug!("No .csmrc found, using defaults");
-                Ok(Config::default())
+                Ok(Self::default())
             }
             Err(e) => Err(e),
-            Ok(csmrc_data) => {
-                let config = serde_yaml_ng::from_str(&csmrc_data)
-                    .map_err(|e| Error::new(ErrorKind::InvalidData, e));
-                debug!("config: {:?}", config);
-                config
-            }
+            Ok(csmrc_data) => Self::parse(&csmrc_data),
+        }
+    }
+
+    /// Parse the YAML `data` of a config file.
+    pub fn parse(data: &str) -> Result<Self, std::io::Error> {
+        serde_yaml_ng::from_str(data).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+impl Config {
+    /// Read the user's ~/.csmrc if it exists, merging with the Default instance for
+    /// Config. Return Err if a config file was found but failed to parse, otherwise
+    /// Ok with the result of merging the config file values with the Default (and
+    /// simply the Default if no config file exists).
+    pub fn from_csmrc() -> Result<Self, std::io::Error> {
+        let config = Config::from(PartialConfig::from_csmrc()?);
+        debug!("config: {:?}", config);
+        Ok(config)
+    }
+
+    /// Check the values in the config beyond what parsing it already checked.
+    /// Files which won't be used aren't looked at.
+    pub fn validate(&self) -> Result<(), std::io::Error> {
+        if self.create_mambarc
+            && let Some(template) = &self.mambarc_template
+            && !Path::new(template).is_file()
+        {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("mambarc_template {} does not exist", template),
+            ));
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let home = PartialConfig::parse("cache_dir: /home\nnoop_mode: true\n").unwrap();
+        let local = PartialConfig::parse("cache_dir: /local\n").unwrap();
+
+        let config = Config::from(home.merge(local));
+        assert_eq!(config.cache_dir.as_deref(), Some("/local"));
+        assert!(config.noop_mode);
+        // Nobody set these, so they are the defaults.
+        assert!(config.create_mambarc);
+        assert_eq!(config.env_file_names, default_env_file_names());
+
+        for empty in ["", "# nothing yet\n"] {
+            let config = Config::from(PartialConfig::parse(empty).unwrap());
+            assert_eq!(config.cache_dir, None);
+            assert!(config.create_mambarc);
+        }
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let csmrc_data = "cache_dir: /x\ncach_dir: /y\nnoop_mode: true\n";
+        assert_eq!(unknown_keys(csmrc_data), vec!["cach_dir".to_string()]);
+        assert!(unknown_keys("not a mapping").is_empty());
     }
 }
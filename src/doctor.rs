@@ -0,0 +1,214 @@
+//! `csm doctor`: check the assumptions csm makes about its environment and
+//! report on each of them.
+
+use crate::csmrc::Config;
+use crate::exit;
+use crate::micromamba::{cache_dir_path, effective_root_prefix, resolve_micromamba};
+use crate::util::homedir;
+
+use std::fs;
+use std::path::Path;
+
+/// The outcome of a single check.
+pub(crate) enum Status {
+    /// All good
+    Pass,
+    /// Something is off, but csm can still work
+    Warn,
+    /// csm will not work like this
+    Fail,
+    /// The check could not be performed
+    Skip,
+}
+
+/// Prints the outcome of each check as it is made, and remembers whether any
+/// of them failed.
+pub(crate) struct Report {
+    failed: bool,
+}
+
+impl Report {
+    pub(crate) fn new() -> Self {
+        Report { failed: false }
+    }
+
+    pub(crate) fn line(&mut self, status: Status, check: &str, detail: impl AsRef<str>) {
+        let label = match status {
+            Status::Pass => " OK ",
+            Status::Warn => "WARN",
+            Status::Fail => {
+                self.failed = true;
+                "FAIL"
+            }
+            Status::Skip => "SKIP",
+        };
+        println!("[{}] {}: {}", label, check, detail.as_ref());
+    }
+
+    /// Fail if any check failed.
+    pub(crate) fn exit_code(&self) -> u8 {
+        if self.failed {
+            exit::FAILURE
+        } else {
+            exit::SUCCESS
+        }
+    }
+}
+
+/// Run the checks with `config`, or if that could not be loaded, with
+/// `defaults`.
+pub fn run(config: Result<Config, std::io::Error>, defaults: Config) -> u8 {
+    let mut report = Report::new();
+
+    match homedir() {
+        Some(home) => report.line(Status::Pass, "Home directory", home.display().to_string()),
+        None => report.line(Status::Fail, "Home directory", "could not be determined"),
+    }
+
+    let config = match config.and_then(|config| config.validate().map(|()| config)) {
+        Ok(config) => {
+            report.line(Status::Pass, ".csmrc", "parsed and valid");
+            config
+        }
+        Err(e) => {
+            report.line(Status::Fail, ".csmrc", e.to_string());
+            // Carry on with the defaults, so the remaining checks still say
+            // something useful.
+            defaults
+        }
+    };
+
+    match cache_dir_path(&config) {
+        Some(cache_dir) if config.noop_mode => {
+            let (status, detail) = describe_cache_dir(&cache_dir);
+            report.line(status, "Cache directory", detail);
+        }
+        Some(cache_dir) => match check_cache_dir(&cache_dir) {
+            Ok(()) => report.line(
+                Status::Pass,
+                "Cache directory",
+                format!("{} is writable and allows executing", cache_dir.display()),
+            ),
+            Err(e) => report.line(
+                Status::Warn,
+                "Cache directory",
+                format!("{}: {}", cache_dir.display(), e),
+            ),
+        },
+        None => report.line(Status::Warn, "Cache directory", "could not be determined"),
+    }
+
+    match resolve_micromamba(&config) {
+        Some((path, version)) => report.line(
+            Status::Pass,
+            "micromamba",
+            format!("{} (version {})", path.display(), version),
+        ),
+        None => report.line(
+            Status::Fail,
+            "micromamba",
+            "not found in $PATH or the cache directory",
+        ),
+    }
+
+    match effective_root_prefix(&config) {
+        None => report.line(Status::Pass, "Root prefix", "micromamba's default"),
+        Some(prefix) if !prefix.is_absolute() => report.line(
+            Status::Warn,
+            "Root prefix",
+            format!("{} is a relative path", prefix.display()),
+        ),
+        Some(prefix) if prefix.exists() && !prefix.is_dir() => report.line(
+            Status::Fail,
+            "Root prefix",
+            format!("{} exists but is not a directory", prefix.display()),
+        ),
+        Some(prefix) => report.line(Status::Pass, "Root prefix", prefix.display().to_string()),
+    }
+
+    // csm does not download micromamba yet, so there is no URL to probe.
+    report.line(
+        Status::Skip,
+        "Network",
+        "csm does not download micromamba yet",
+    );
+
+    report.exit_code()
+}
+
+/// Check that we can write to the cache directory and run programs from it,
+/// since csm keeps its copy of micromamba there. On Windows there is no
+/// noexec, so only writing is checked.
+fn check_cache_dir(cache_dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(cache_dir)?;
+    let probe = cache_dir.join(".csm-doctor-probe");
+    fs::write(&probe, "#!/bin/sh\nexit 0\n")?;
+    let result = check_exec(&probe);
+    fs::remove_file(&probe)?;
+    result
+}
+
+/// What we can tell about the cache directory without writing to it, for
+/// no-op mode.
+fn describe_cache_dir(cache_dir: &Path) -> (Status, String) {
+    match fs::metadata(cache_dir) {
+        Ok(metadata) if metadata.is_dir() => (
+            Status::Pass,
+            format!(
+                "{} exists ({}), not checking writing or executing in no-op mode",
+                cache_dir.display(),
+                describe_permissions(&metadata)
+            ),
+        ),
+        Ok(_) => (
+            Status::Warn,
+            format!("{} exists but is not a directory", cache_dir.display()),
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+            Status::Skip,
+            format!(
+                "{} does not exist yet, not creating it in no-op mode",
+                cache_dir.display()
+            ),
+        ),
+        Err(e) => (Status::Warn, format!("{}: {}", cache_dir.display(), e)),
+    }
+}
+
+#[cfg(unix)]
+fn describe_permissions(metadata: &fs::Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    format!(
+        "mode {:o}, owner {}",
+        metadata.mode() & 0o7777,
+        metadata.uid()
+    )
+}
+
+#[cfg(not(unix))]
+fn describe_permissions(metadata: &fs::Metadata) -> String {
+    if metadata.permissions().readonly() {
+        "read-only".to_string()
+    } else {
+        "not read-only".to_string()
+    }
+}
+
+#[cfg(unix)]
+fn check_exec(probe: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(probe, fs::Permissions::from_mode(0o755))?;
+    let status = std::process::Command::new(probe).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other("could not run a program from it"))
+    }
+}
+
+#[cfg(not(unix))]
+fn check_exec(_probe: &Path) -> std::io::Result<()> {
+    Ok(())
+}
@@ -1,11 +1,196 @@
 use crate::csmrc::Config;
-use crate::micromamba::micromamba;
+use crate::exit;
+use crate::json::Json;
+use crate::micromamba::{
+    MicromambaResult, check_root_prefix_writable, effective_root_prefix, lock_root_prefix,
+    micromamba, micromamba_at, micromamba_in, micromamba_output, resolve_micromamba,
+    shell_command_line,
+};
+use crate::util::{
+    expand_vars, expand_vars_and_hostname, glob_match, read_env_file, sha256_hex, write_atomically,
+};
 
-use log::{debug, error};
+use clap::builder::PossibleValuesParser;
+use log::{debug, error, info, warn};
 use serde::Deserialize;
-use std::io::{Error, ErrorKind};
-use std::path::Component;
-use std::process::ExitCode;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Everything that can go wrong in the `env` subcommands.
+#[derive(Debug)]
+pub enum EnvError {
+    /// No environment name was given, and none could be determined
+    NameUnresolvable,
+    /// robotmk-env.yaml exists, but could not be read
+    YamlRead(std::io::Error),
+    /// robotmk-env.yaml could be read, but not parsed
+    YamlParse(serde_yaml_ng::Error),
+    /// The environment to be created exists already
+    AlreadyExists(String),
+    /// An environment we were asked to use does not exist
+    DoesNotExist(String),
+    /// --name-from named a source which has no name
+    NameSourceEmpty(NameSource),
+    /// The name in robotmk-env.yaml refers to an environment variable which is
+    /// not set
+    NameVariableUnset(String),
+    /// `env_name_suffix` refers to an environment variable which is not set
+    SuffixVariableUnset(String),
+    /// The root prefix could not be locked against other csm processes
+    Lock(std::io::Error),
+    /// The root prefix can't be written to
+    RootPrefixNotWritable(PathBuf),
+    /// The --env-file could not be read or parsed
+    EnvFile(String),
+    /// The name can't be used for an environment directory
+    InvalidName(String),
+    /// An archive could not be unpacked
+    UnpackFailed(String),
+    /// The environment exported with --from-env could not be saved
+    Export(std::io::Error),
+    /// The --manifest could not be written, or the spec file for it read
+    Manifest(PathBuf, std::io::Error),
+    /// With --keep-going, this many of that many environments failed
+    SomeFailed(usize, usize),
+    /// The user did not confirm doing this, or could not be asked
+    NotConfirmed(String),
+    /// micromamba's output was not what we expected
+    UnexpectedOutput(String),
+    /// A directory we were given does not exist, or is not a directory
+    NotADirectory(PathBuf),
+    /// micromamba ran, but was not successful
+    MicromambaFailed(ExitStatus),
+    /// There is no `micromamba` anywhere we looked, for these reasons
+    MicromambaNotFound(String),
+    /// We found a `micromamba`, but could not run it, for these reasons
+    MicromambaUnavailable(String),
+    /// micromamba took too long, so we killed it
+    MicromambaTimedOut,
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NameUnresolvable => write!(
+                f,
+                "No environment name could be determined. You can specify one with --name"
+            ),
+            Self::YamlRead(e) => write!(f, "Could not read robotmk-env.yaml: {}", e),
+            Self::YamlParse(e) => write!(f, "Could not parse robotmk-env.yaml: {}", e),
+            Self::AlreadyExists(name) => write!(
+                f,
+                "Environment '{}' already exists. Use --force to replace it",
+                name
+            ),
+            Self::DoesNotExist(name) => write!(f, "Environment '{}' does not exist", name),
+            Self::NameSourceEmpty(source) => {
+                write!(f, "No environment name could be taken from {}", source)
+            }
+            Self::NameVariableUnset(var) => write!(
+                f,
+                "The name in robotmk-env.yaml refers to the environment variable {}, which is not set",
+                var
+            ),
+            Self::SuffixVariableUnset(var) => write!(
+                f,
+                "env_name_suffix in .csmrc refers to the environment variable {}, which is not set",
+                var
+            ),
+            Self::Lock(e) => write!(f, "Could not lock the root prefix: {}", e),
+            Self::RootPrefixNotWritable(path) => write!(
+                f,
+                "The root prefix {} is not writable, so no environments can be changed in it",
+                path.display()
+            ),
+            Self::EnvFile(e) => write!(f, "Could not read environment file {}", e),
+            Self::InvalidName(name) => write!(
+                f,
+                "'{}' is not a valid environment name: it must not be empty, . or .., or contain a path separator",
+                name
+            ),
+            Self::UnpackFailed(reason) => write!(f, "Could not unpack: {}", reason),
+            Self::Export(e) => write!(f, "Could not save the exported environment: {}", e),
+            Self::Manifest(path, e) => {
+                write!(f, "Could not write the manifest {}: {}", path.display(), e)
+            }
+            Self::SomeFailed(failed, total) => {
+                write!(f, "{} of {} environments failed", failed, total)
+            }
+            Self::NotConfirmed(what) => write!(
+                f,
+                "Not {} without confirmation. Use --yes to go ahead anyway",
+                what
+            ),
+            Self::UnexpectedOutput(e) => write!(f, "Could not parse the output of {}", e),
+            Self::NotADirectory(path) => write!(f, "{} is not a directory", path.display()),
+            Self::MicromambaFailed(exit_status) => write!(f, "micromamba failed: {}", exit_status),
+            Self::MicromambaNotFound(reasons) => {
+                write!(f, "micromamba could not be found ({})", reasons)
+            }
+            Self::MicromambaUnavailable(reasons) => {
+                write!(f, "micromamba could not be run ({})", reasons)
+            }
+            Self::MicromambaTimedOut => write!(f, "micromamba timed out"),
+        }
+    }
+}
+
+impl EnvError {
+    /// The exit code csm should exit with because of this error. When
+    /// micromamba itself failed, we pass its exit code on.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::MicromambaFailed(exit_status) => MicromambaResult::Ok(*exit_status).exit_code(),
+            Self::MicromambaNotFound(_) => exit::NOT_FOUND,
+            Self::MicromambaUnavailable(_) => exit::COULD_NOT_RUN,
+            Self::MicromambaTimedOut => MicromambaResult::TimedOut.exit_code(),
+            // It comes from .csmrc.
+            Self::SuffixVariableUnset(_) => exit::CONFIG,
+            _ => exit::FAILURE,
+        }
+    }
+}
+
+/// Translate the result of running micromamba into ours.
+fn check(result: MicromambaResult) -> Result<(), EnvError> {
+    match result {
+        MicromambaResult::Ok(exit_status) if exit_status.success() => Ok(()),
+        MicromambaResult::Ok(exit_status) => Err(EnvError::MicromambaFailed(exit_status)),
+        MicromambaResult::Noop | MicromambaResult::Spawned(_) => Ok(()),
+        MicromambaResult::NotFound(reasons) => Err(EnvError::MicromambaNotFound(reasons)),
+        MicromambaResult::CouldNotRun(reasons) => Err(EnvError::MicromambaUnavailable(reasons)),
+        MicromambaResult::TimedOut => Err(EnvError::MicromambaTimedOut),
+    }
+}
+
+/// The conda platforms (subdirs) micromamba can create environments for.
+pub const KNOWN_PLATFORMS: &[&str] = &[
+    "noarch",
+    "linux-32",
+    "linux-64",
+    "linux-aarch64",
+    "linux-armv6l",
+    "linux-armv7l",
+    "linux-ppc64le",
+    "linux-s390x",
+    "linux-riscv64",
+    "osx-64",
+    "osx-arm64",
+    "win-32",
+    "win-64",
+    "win-arm64",
+    "emscripten-wasm32",
+    "wasi-wasm32",
+    "zos-z",
+];
 
 #[derive(Debug, clap::Subcommand)]
 pub enum Subcommand {
@@ -16,13 +201,23 @@ pub enum Subcommand {
     /// Deactivate an environment
     Deactivate,
     /// Run an executable in an environment
-    Run,
-    /// ???
-    Pack,
-    /// ???
-    Unpack,
+    Run(RunArgs),
+    /// Update the packages in an existing environment
+    Update(UpdateArgs),
+    /// Install more packages into an existing environment
+    Install(InstallArgs),
+    /// Remove an environment
+    Remove(RemoveArgs),
+    /// Rename an environment, by cloning it and removing the original
+    Rename(RenameArgs),
+    /// Pack an environment into a relocatable archive, using conda-pack
+    Pack(PackArgs),
+    /// Unpack an archive made by `csm env pack` into the root prefix
+    Unpack(UnpackArgs),
     /// List existing environments
-    List,
+    List(ListArgs),
+    /// Show the packages which differ between two environments
+    Diff(DiffArgs),
     /// Display information about the micromamba setup
     Info,
 }
@@ -33,87 +228,1580 @@ pub struct CreateArgs {
     /// look to robotmk-env.yaml for a "name" field to use instead. As a last
     /// resort, the current directory name will be used
     #[arg(short, long)]
-    name: Option<String>,
+    pub name: Option<String>,
+
+    /// Take the environment name only from this source, instead of the first
+    /// which has one
+    #[arg(long, value_name = "SOURCE", default_value = "auto")]
+    pub name_from: NameSource,
+
+    /// If specified, hand this environment file to micromamba as-is instead
+    /// of building the environment from the fields csm understands in
+    /// robotmk-env.yaml
+    #[arg(short, long)]
+    pub file: Option<String>,
+
+    /// Instead of robotmk-env.yaml, recreate this existing environment from
+    /// its history, i.e. only the packages which were explicitly asked for
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["file", "print_cmd"])]
+    pub from_env: Option<String>,
+
+    /// The project directory, in which to look for robotmk-env.yaml and whose
+    /// name is the last resort for the environment name
+    #[arg(long, default_value = ".")]
+    pub path: String,
+
+    /// Create the environment at this path instead of by name in the root
+    /// prefix, e.g. next to the project
+    #[arg(long, value_name = "PATH", conflicts_with = "name")]
+    pub prefix: Option<PathBuf>,
+
+    /// Create the environment even if one with the same name already exists,
+    /// replacing it
+    #[arg(long)]
+    pub force: bool,
+
+    /// If an environment with the same name exists already, leave it alone and
+    /// exit successfully instead of failing
+    #[arg(long, conflicts_with = "force")]
+    pub if_missing: bool,
+
+    /// How micromamba should prioritize the channels when solving, overriding
+    /// `channel_priority` in robotmk-env.yaml. By default, micromamba's own
+    /// default applies
+    #[arg(long, value_name = "PRIORITY")]
+    pub channel_priority: Option<ChannelPriority>,
+
+    /// Create the environment for this platform (conda subdir) rather than the
+    /// one csm runs on
+    #[arg(long, value_parser = PossibleValuesParser::new(KNOWN_PLATFORMS))]
+    pub platform: Option<String>,
+
+    /// Only install packages which are already in micromamba's package cache,
+    /// never downloading any (micromamba's `--offline`). This has nothing to do
+    /// with how csm obtains micromamba itself
+    #[arg(long)]
+    pub solver_offline: bool,
+
+    /// If micromamba fails, run it again up to this many times, in case the
+    /// failure was a transient one
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+
+    /// If creating the environment fails, try again with `--solver-offline`.
+    /// Any failure triggers this, since csm can't tell network errors from
+    /// others
+    #[arg(long, conflicts_with = "solver_offline")]
+    pub offline_fallback: bool,
+
+    /// After creating the environment, run this command in it, overriding
+    /// `post_create` in .csmrc. It is split at whitespace, without any shell
+    /// quoting. If it fails, so does the create
+    #[arg(long, value_name = "COMMAND")]
+    pub post_create: Option<String>,
+
+    /// Print the micromamba command line which would create the environment,
+    /// quoted for a POSIX shell, and exit without running it
+    #[arg(long)]
+    pub print_cmd: bool,
+
+    /// After creating the environment, write a JSON manifest of how it was
+    /// built to this file: the environment, the spec file (or the environment
+    /// given to --from-env) and its SHA-256, the channels, the micromamba
+    /// version and the time
+    #[arg(long, value_name = "PATH")]
+    pub manifest: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct UpdateArgs {
+    /// The name of an environment to update. Can be given more than once to
+    /// update several environments. If not specified, it is determined the
+    /// same way as for `csm env create`
+    #[arg(short, long)]
+    pub name: Vec<String>,
+
+    /// The project directory. If it has a robotmk-env.yaml, the project's
+    /// environment is brought in line with it. All packages are updated in
+    /// any other environment
+    #[arg(long, default_value = ".")]
+    pub path: String,
+
+    /// If micromamba fails, run it again up to this many times, in case the
+    /// failure was a transient one
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+
+    /// When updating several environments, carry on after one fails instead
+    /// of stopping, and fail at the end if any did
+    #[arg(short, long)]
+    pub keep_going: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct InstallArgs {
+    /// The name of the environment to install into. If not specified, it is
+    /// determined the same way as for `csm env create`
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// The project directory, used to determine the environment name
+    #[arg(long, default_value = ".")]
+    pub path: String,
+
+    /// Look for the packages in this channel too. Can be given more than once
+    #[arg(short, long = "channel", value_name = "CHANNEL")]
+    pub channels: Vec<String>,
+
+    /// The packages to install, as micromamba match specs, e.g. `requests` or
+    /// `python=3.12`
+    #[arg(required = true)]
+    pub packages: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct DiffArgs {
+    /// The name of the first environment
+    pub a: String,
+
+    /// The name of the second environment
+    pub b: String,
+
+    /// Print the differences as a JSON array of objects with `name`, `a` and
+    /// `b` fields, the latter two being the versions in each environment or
+    /// null
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RemoveArgs {
+    /// The name of the environment to remove. If not specified, it is
+    /// determined the same way as for `csm env create`
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// The project directory, used to determine the environment name
+    #[arg(long, default_value = ".")]
+    pub path: String,
+
+    /// Remove the environment at this path instead of one by name
+    #[arg(long, value_name = "PATH", conflicts_with = "name")]
+    pub prefix: Option<PathBuf>,
+
+    /// Remove all environments whose names match this glob, e.g.
+    /// 'robot-ci-*', after showing them and asking once for confirmation
+    #[arg(long, value_name = "GLOB", conflicts_with_all = ["name", "prefix"])]
+    pub pattern: Option<String>,
+
+    /// With --pattern, don't ask for confirmation
+    #[arg(short, long, requires = "pattern")]
+    pub yes: bool,
+
+    /// With --pattern, carry on after removing one environment fails instead
+    /// of stopping, and fail at the end if any did
+    #[arg(short, long, requires = "pattern")]
+    pub keep_going: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RenameArgs {
+    /// The current name of the environment
+    pub old: String,
+
+    /// The name to give it
+    pub new: String,
+
+    /// Don't ask for confirmation
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct PackArgs {
+    /// The name of the environment to pack. If not specified, it is
+    /// determined the same way as for `csm env create`
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// The project directory, used to determine the environment name
+    #[arg(long, default_value = ".")]
+    pub path: String,
+
+    /// The archive to create, e.g. `robot.tar.gz`. conda-pack picks the format
+    /// from the extension
+    #[arg(short, long)]
+    pub output: String,
+
+    /// Overwrite the archive if it exists already
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct UnpackArgs {
+    /// The archive to unpack, as made by `csm env pack`
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// The name of the environment to unpack into. By default, this is the
+    /// name of the archive without its extension
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// Replace the environment if it exists already
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ListArgs {
+    /// Print the environments as a JSON array of objects with `name` and
+    /// `prefix` fields
+    #[arg(long)]
+    pub json: bool,
+
+    /// Also count the packages in each environment. This runs micromamba once
+    /// per environment, a few of them at a time
+    #[arg(long)]
+    pub counts: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RunArgs {
+    /// The name of the environment to run in. If not specified, it is
+    /// determined the same way as for `csm env create`
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// The project directory, used to determine the environment name
+    #[arg(long, default_value = ".")]
+    pub path: String,
+
+    /// Run in the environment at this path instead of one by name
+    #[arg(long, value_name = "PATH", conflicts_with = "name")]
+    pub prefix: Option<PathBuf>,
+
+    /// Run the command in this directory instead of the current one
+    #[arg(long, value_name = "DIR")]
+    pub cwd: Option<PathBuf>,
+
+    /// Set the environment variables from this file of KEY=VALUE lines for
+    /// the command
+    #[arg(long, value_name = "PATH")]
+    pub env_file: Option<PathBuf>,
+
+    /// The command to run, and its arguments. Put it after `--` so that its
+    /// arguments aren't taken for csm's
+    #[arg(required = true, trailing_var_arg = true)]
+    pub command: Vec<String>,
 }
 
 /// Contains the fields we need from a parsed `robotmk-env.yml` file.
 #[derive(Deserialize)]
 struct RobotmkEnv {
-    /// The name of the environment
-    name: Option<String>,
+    /// Channels to pass to micromamba, in order
+    #[serde(default)]
+    channels: Vec<String>,
+
+    /// The packages to install into the environment
+    #[serde(default)]
+    dependencies: Vec<Dependency>,
+
+    /// How micromamba should prioritize the channels when solving
+    channel_priority: Option<ChannelPriority>,
+
+    /// Metadata for Robotmk
+    robotmk: Option<RobotmkMetadata>,
 }
 
-/// Attempt to parse a robotmk-env.yaml in the current directory.
-fn parse_robotmk_env_yaml() -> Result<RobotmkEnv, std::io::Error> {
-    // TODO: Should we handle .yml too?
-    let contents = std::fs::read_to_string("robotmk-env.yaml")?;
-    serde_yaml_ng::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+/// The `robotmk` section of robotmk-env.yaml, with metadata for Robotmk which
+/// csm itself doesn't act on. Keys csm doesn't know are ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct RobotmkMetadata {
+    /// The category of the robot
+    pub category: Option<String>,
+    /// A tag identifying the build of the robot
+    pub build_tag: Option<String>,
 }
 
-pub fn determine_env_name(args: CreateArgs) -> Option<String> {
-    // If someone gave an explicit --name, use that first.
-    if let Some(name) = args.name {
-        debug!("Using '{}' as env name, given by CLI argument", name);
-        return Some(name);
+impl RobotmkMetadata {
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("category", Json::from(self.category.clone())),
+            ("build_tag", Json::from(self.build_tag.clone())),
+        ])
     }
+}
 
-    // Fallback 1: Look for a name key in robotmk-env.yaml
-    // We ignore errors from parse_robotmk_env_yaml() here, we'll fall back
-    // below if we can't parse it for some reason
-    if let Ok(env) = parse_robotmk_env_yaml()
-        && let Some(name) = env.name
-    {
-        debug!("Using '{}' as env name, found in robotmk-env.yaml", name);
-        return Some(name);
+/// micromamba's channel priority modes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelPriority {
+    /// Only take a package from the first channel which has it
+    Strict,
+    /// Prefer earlier channels, but take newer versions from later ones
+    Flexible,
+    /// Ignore the channel order, only the versions count
+    Disabled,
+}
+
+impl ChannelPriority {
+    /// The micromamba arguments selecting this mode.
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            Self::Strict => &["--strict-channel-priority"],
+            Self::Flexible => &["--channel-priority", "flexible"],
+            Self::Disabled => &["--no-channel-priority"],
+        }
     }
+}
 
-    // Fallback 2: Current directory name
-    match std::env::current_dir() {
+/// An entry of the `dependencies` list in robotmk-env.yaml.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Dependency {
+    /// A conda package spec, like `python=3.12`
+    Package(String),
+    /// Packages to install with pip once the environment exists
+    Pip { pip: Vec<String> },
+    /// Anything else, which csm can't express on the micromamba command line
+    Other(serde_yaml_ng::Value),
+}
+
+/// The parsed output of `micromamba env list --json`.
+#[derive(Deserialize)]
+struct EnvList {
+    /// The prefixes of all environments micromamba knows about
+    envs: Vec<PathBuf>,
+}
+
+/// An environment micromamba knows about.
+struct EnvInfo {
+    /// The name of the environment, if it can be activated by name
+    name: Option<String>,
+    prefix: PathBuf,
+}
+
+/// Return the environments micromamba knows about.
+///
+/// Environments in the root prefix's `envs` directory are named after their
+/// directory, and the root prefix itself is `base`. Any others have no name.
+fn list_envs(config: &Config) -> Result<Vec<EnvInfo>, EnvError> {
+    let output = match micromamba_output(config, vec!["env", "list", "--json"]) {
+        Ok(output) => output,
+        Err(result) => return check(result).map(|()| vec![]),
+    };
+    check(MicromambaResult::Ok(output.status))?;
+    // JSON is (for our purposes) YAML, so we don't need a separate parser.
+    // Without the list, we can't tell whether an environment exists, and
+    // assuming none do could replace one, so this is an error.
+    let env_list: EnvList = serde_yaml_ng::from_slice(&output.stdout)
+        .map_err(|e| EnvError::UnexpectedOutput(format!("micromamba env list: {}", e)))?;
+    let envs_dirs: Vec<&Path> = env_list
+        .envs
+        .iter()
+        .filter_map(|prefix| prefix.parent())
+        .filter(|parent| parent.file_name() == Some("envs".as_ref()))
+        .collect();
+    Ok(env_list
+        .envs
+        .iter()
+        .map(|prefix| {
+            let name = if envs_dirs.contains(&prefix.join("envs").as_path()) {
+                Some("base".to_string())
+            } else if prefix.parent().and_then(Path::file_name) == Some("envs".as_ref()) {
+                prefix
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .map(String::from)
+            } else {
+                None
+            };
+            EnvInfo {
+                name,
+                prefix: prefix.clone(),
+            }
+        })
+        .collect())
+}
+
+/// Return the names of the existing environments in the root prefix.
+///
+/// Environments outside of the root prefix's `envs` directory (including the
+/// base environment) have no name we could collide with, so they are skipped.
+pub fn existing_env_names(config: &Config) -> Result<Vec<String>, EnvError> {
+    Ok(list_envs(config)?
+        .into_iter()
+        .filter(|env| env.prefix.parent().and_then(Path::file_name) == Some("envs".as_ref()))
+        .filter_map(|env| env.name)
+        .collect())
+}
+
+/// Return the number of packages installed in the environment at `prefix`, or
+/// `None` if micromamba could not tell us.
+fn package_count(config: &Config, prefix: &Path) -> Option<usize> {
+    let prefix = prefix.to_str()?;
+    let output = micromamba_output(config, vec!["list", "--prefix", prefix, "--json"]).ok()?;
+    if !output.status.success() {
+        warn!("Could not list the packages in {}", prefix);
+        return None;
+    }
+    match serde_yaml_ng::from_slice::<Vec<serde_yaml_ng::Value>>(&output.stdout) {
+        Ok(packages) => Some(packages.len()),
         Err(e) => {
-            debug!("Could not determine current directory: {}", e);
+            warn!("Could not parse the output of micromamba list: {}", e);
             None
         }
-        Ok(pathbuf) => match pathbuf.components().next_back() {
-            Some(Component::Normal(s)) => match s.to_str().map(String::from) {
-                Some(name) => {
-                    debug!(
-                        "Using '{}' as env name, taken from current directory name",
-                        name
-                    );
-                    Some(name)
-                }
-                _ => None, // Likely could not convert path name to utf-8
+    }
+}
+
+/// A package in the output of `micromamba list --json`.
+#[derive(Deserialize)]
+struct PackageInfo {
+    name: String,
+    version: String,
+}
+
+/// Return the packages in the environment `env_name`, mapped to their
+/// versions.
+fn package_versions(config: &Config, env_name: &str) -> Result<BTreeMap<String, String>, EnvError> {
+    let output = match micromamba_output(config, vec!["list", "--name", env_name, "--json"]) {
+        Ok(output) => output,
+        Err(result) => return check(result).map(|()| BTreeMap::new()),
+    };
+    check(MicromambaResult::Ok(output.status))?;
+    let packages: Vec<PackageInfo> = serde_yaml_ng::from_slice(&output.stdout)
+        .map_err(|e| EnvError::UnexpectedOutput(format!("micromamba list: {}", e)))?;
+    Ok(packages.into_iter().map(|p| (p.name, p.version)).collect())
+}
+
+/// The packages which are only in one of `a` and `b`, or in different
+/// versions, with their versions in each, sorted by name.
+fn diff_packages<'a>(
+    a: &'a BTreeMap<String, String>,
+    b: &'a BTreeMap<String, String>,
+) -> Vec<(&'a str, Option<&'a str>, Option<&'a str>)> {
+    let names: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    names
+        .into_iter()
+        .map(|name| {
+            (
+                name.as_str(),
+                a.get(name).map(String::as_str),
+                b.get(name).map(String::as_str),
+            )
+        })
+        .filter(|(_, a, b)| a != b)
+        .collect()
+}
+
+/// How many `micromamba list` we run at the same time for
+/// [`package_counts()`].
+const MAX_PARALLEL_COUNTS: usize = 4;
+
+/// Like [`package_count()`] for each of `envs`, in the same order, but a few
+/// at a time, since each takes a while. Each worker takes the next environment
+/// as soon as it is done with one, so a slow one only holds up its worker.
+fn package_counts(config: &Config, envs: &[EnvInfo]) -> Vec<Option<usize>> {
+    let next = AtomicUsize::new(0);
+    let mut counts = vec![None; envs.len()];
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..MAX_PARALLEL_COUNTS.min(envs.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(env) = envs.get(i) else {
+                            return done;
+                        };
+                        done.push((i, package_count(config, &env.prefix)));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, count) in worker.join().unwrap_or_default() {
+                counts[i] = count;
+            }
+        }
+    });
+    counts
+}
+
+/// The top-level keys we expect in a robotmk-env.yaml: the ones we model in
+/// [`RobotmkEnv`], plus the rest of the conda environment file format, which
+/// micromamba deals with.
+const KNOWN_ROBOTMK_ENV_KEYS: &[&str] = &[
+    "name",
+    "channels",
+    "channel_priority",
+    "dependencies",
+    "prefix",
+    "variables",
+    "robotmk",
+];
+
+/// Return the top-level keys of a parsed robotmk-env.yaml which we don't know
+/// about. These are most likely typos, like `namme:`.
+fn unknown_keys(value: &serde_yaml_ng::Value) -> Vec<String> {
+    let Some(mapping) = value.as_mapping() else {
+        return vec![];
+    };
+    mapping
+        .keys()
+        .map(|key| match key.as_str() {
+            Some(key) => key.to_string(),
+            None => format!("{:?}", key),
+        })
+        .filter(|key| !KNOWN_ROBOTMK_ENV_KEYS.contains(&key.as_str()))
+        .collect()
+}
+
+/// Find the environment file of the project in `dir`: the first of the
+/// `env_file_names` in the config which exists there.
+pub fn env_file_path(config: &Config, dir: &Path) -> Option<PathBuf> {
+    config
+        .env_file_names
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Attempt to parse the environment file (usually robotmk-env.yaml) in `dir`.
+fn parse_robotmk_env_yaml(config: &Config, dir: &Path) -> Result<RobotmkEnv, EnvError> {
+    let Some(path) = env_file_path(config, dir) else {
+        return Err(EnvError::YamlRead(ErrorKind::NotFound.into()));
+    };
+    let contents = std::fs::read_to_string(&path).map_err(EnvError::YamlRead)?;
+    let value: serde_yaml_ng::Value =
+        serde_yaml_ng::from_str(&contents).map_err(EnvError::YamlParse)?;
+    for key in unknown_keys(&value) {
+        warn!("Ignoring unknown key '{}' in {}", key, path.display());
+    }
+    let robotmk_env: RobotmkEnv = serde_yaml_ng::from_value(value).map_err(EnvError::YamlParse)?;
+    if let Some(metadata) = &robotmk_env.robotmk {
+        debug!("Robotmk metadata in {}: {:?}", path.display(), metadata);
+    }
+    Ok(robotmk_env)
+}
+
+/// Parse only the part `T` of the environment file in `dir`, so that mistakes
+/// elsewhere in it don't keep us from using this part. `None` if there is no
+/// environment file, or it is empty.
+fn parse_env_file_part<T: DeserializeOwned>(
+    config: &Config,
+    dir: &Path,
+) -> Result<Option<T>, EnvError> {
+    let Some(path) = env_file_path(config, dir) else {
+        return Ok(None);
+    };
+    let contents = std::fs::read_to_string(&path).map_err(EnvError::YamlRead)?;
+    let value: serde_yaml_ng::Value =
+        serde_yaml_ng::from_str(&contents).map_err(EnvError::YamlParse)?;
+    if value.is_null() {
+        return Ok(None);
+    }
+    serde_yaml_ng::from_value(value)
+        .map(Some)
+        .map_err(EnvError::YamlParse)
+}
+
+/// Only the `robotmk` section of robotmk-env.yaml.
+#[derive(Deserialize)]
+struct EnvFileMetadata {
+    robotmk: Option<RobotmkMetadata>,
+}
+
+/// The `robotmk` section of the environment file in `dir`, if it has one.
+pub fn robotmk_metadata(config: &Config, dir: &Path) -> Result<Option<RobotmkMetadata>, EnvError> {
+    Ok(parse_env_file_part::<EnvFileMetadata>(config, dir)?.and_then(|part| part.robotmk))
+}
+
+/// Check that the environment file in `dir` can be read and parsed, and
+/// return its path, or `None` if there is none.
+pub fn check_env_file(config: &Config, dir: &Path) -> Result<Option<PathBuf>, EnvError> {
+    let Some(path) = env_file_path(config, dir) else {
+        return Ok(None);
+    };
+    parse_robotmk_env_yaml(config, dir).map(|_| Some(path))
+}
+
+/// Determine the environment name for the project in `dir`: `name` if given,
+/// otherwise the name from its robotmk-env.yaml, otherwise the name of `dir`.
+pub fn determine_env_name(
+    config: &Config,
+    name: Option<String>,
+    dir: &Path,
+) -> Result<String, EnvError> {
+    let name = resolve_env_name_in(config, NameSource::Auto, name, dir)?
+        .ok_or(EnvError::NameUnresolvable)?;
+    add_name_suffix(config, name)
+}
+
+/// Only the name from robotmk-env.yaml.
+#[derive(Deserialize)]
+struct EnvFileName {
+    name: Option<String>,
+}
+
+/// Like [`resolve_env_name_from()`], but read the name from the environment
+/// file in `dir` if `source` calls for it.
+fn resolve_env_name_in(
+    config: &Config,
+    source: NameSource,
+    name: Option<String>,
+    dir: &Path,
+) -> Result<Option<String>, EnvError> {
+    let yaml_name = match (source, &name) {
+        (NameSource::Auto, None) | (NameSource::Yaml, _) => {
+            parse_env_file_part::<EnvFileName>(config, dir)?.and_then(|part| part.name)
+        }
+        _ => None,
+    };
+    resolve_env_name_from(source, name, yaml_name.as_deref(), dir)
+}
+
+/// Append the configured `env_name_suffix`, if any, to the environment name
+/// `name`. This applies to all names csm works out, including ones given with
+/// --name, so that every subcommand agrees on them.
+fn add_name_suffix(config: &Config, name: String) -> Result<String, EnvError> {
+    let Some(suffix) = &config.env_name_suffix else {
+        return Ok(name);
+    };
+    let suffix = expand_vars_and_hostname(suffix).map_err(EnvError::SuffixVariableUnset)?;
+    let name = format!("{}-{}", name, suffix);
+    debug!("Using '{}' as env name, with env_name_suffix", name);
+    Ok(name)
+}
+
+/// Where the environment name comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum NameSource {
+    /// The first of --name, the name in robotmk-env.yaml and the directory
+    /// name which is available
+    #[default]
+    Auto,
+    /// Only --name
+    Arg,
+    /// Only the name in robotmk-env.yaml
+    Yaml,
+    /// Only the name of the project directory
+    Dir,
+}
+
+impl fmt::Display for NameSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Arg => write!(f, "the --name argument"),
+            Self::Yaml => write!(f, "robotmk-env.yaml"),
+            Self::Dir => write!(f, "the directory name"),
+        }
+    }
+}
+
+/// Resolve the environment name from `source`: the name given with --name,
+/// `yaml_name` from robotmk-env.yaml, or the name of `dir`. Unless `source`
+/// is [`NameSource::Auto`], it is an error if it has no name.
+///
+/// Environment variables in the name from robotmk-env.yaml are expanded, and
+/// it is an error if any of them is not set.
+fn resolve_env_name_from(
+    source: NameSource,
+    name: Option<String>,
+    yaml_name: Option<&str>,
+    dir: &Path,
+) -> Result<Option<String>, EnvError> {
+    let resolved = match source {
+        NameSource::Auto => match name {
+            // If someone gave an explicit --name, use that first.
+            Some(name) => Some(name_from_arg(name)),
+            // Fallback 1: Look for a name key in robotmk-env.yaml
+            // Fallback 2: Directory name.
+            None => match name_from_yaml(yaml_name)? {
+                Some(name) => Some(name),
+                None => name_from_dir(dir),
             },
-            _ => None, // In theory, I think this should never happen
         },
+        NameSource::Arg => name.map(name_from_arg),
+        NameSource::Yaml => name_from_yaml(yaml_name)?,
+        NameSource::Dir => name_from_dir(dir),
+    };
+    match resolved {
+        None if source != NameSource::Auto => Err(EnvError::NameSourceEmpty(source)),
+        resolved => Ok(resolved),
+    }
+}
+
+fn name_from_arg(name: String) -> String {
+    debug!("Using '{}' as env name, given by CLI argument", name);
+    name
+}
+
+fn name_from_yaml(name: Option<&str>) -> Result<Option<String>, EnvError> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    let name = expand_vars(name).map_err(EnvError::NameVariableUnset)?;
+    debug!("Using '{}' as env name, found in robotmk-env.yaml", name);
+    Ok(Some(name))
+}
+
+fn name_from_dir(dir: &Path) -> Option<String> {
+    // std::path::absolute() keeps `..` components and doesn't need the path
+    // to exist, so resolve those fully if needed.
+    let absolute = std::path::absolute(dir).and_then(|path| match path.components().next_back() {
+        Some(Component::Normal(_)) => Ok(path),
+        _ => path.canonicalize(),
+    });
+    let pathbuf = match absolute {
+        Ok(pathbuf) => pathbuf,
+        Err(e) => {
+            debug!("Could not determine directory {}: {}", dir.display(), e);
+            return None;
+        }
+    };
+    let Some(path) = pathbuf.to_str() else {
+        debug!("Directory {} is not valid UTF-8", pathbuf.display());
+        return None;
+    };
+    match last_dir_name(path) {
+        Some(name) => {
+            debug!("Using '{}' as env name, taken from directory name", name);
+            Some(name.to_string())
+        }
+        None => {
+            debug!("Directory {} has no name to use as env name", path);
+            None
+        }
     }
 }
 
-pub fn run(config: Config, subcommand: Subcommand) -> ExitCode {
+/// Return the name of the last directory in `path`, or `None` if it has none,
+/// like `/`, `C:\` or `\\server\share`.
+///
+/// This works on the string rather than on [`Path`] so that Windows paths are
+/// handled the same on every platform (and can be tested everywhere). Both `/`
+/// and `\` are separators, and trailing ones are ignored.
+fn last_dir_name(path: &str) -> Option<&str> {
+    let is_separator = |c: char| c == '/' || c == '\\';
+    // Verbatim (\\?\) and device (\\.\) prefixes only change how the rest is
+    // interpreted, but UNC paths start with a server and share, which name a
+    // volume rather than a directory.
+    let rest = path
+        .strip_prefix(r"\\?\")
+        .or_else(|| path.strip_prefix(r"\\.\"))
+        .unwrap_or(path);
+    let rest = match rest
+        .strip_prefix("UNC\\")
+        .or_else(|| rest.strip_prefix(r"\\"))
+    {
+        Some(unc) => unc.splitn(3, is_separator).nth(2).unwrap_or(""),
+        None => rest,
+    };
+    match rest
+        .trim_end_matches(is_separator)
+        .rsplit(is_separator)
+        .next()?
+    {
+        "" | "." | ".." => None,
+        drive if drive.len() == 2 && drive.ends_with(':') => None,
+        name => Some(name),
+    }
+}
+
+/// Build the micromamba arguments for creating `target`.
+///
+/// With an explicit `file`, the whole file is delegated to micromamba.
+/// Otherwise we build the command ourselves from the channels and dependencies
+/// in robotmk-env.yaml (if any).
+fn create_args(target: &EnvTarget, file: Option<&str>, env: Option<&RobotmkEnv>) -> Vec<String> {
+    let mut args: Vec<String> = match file {
+        Some(file) => vec!["env".into(), "create".into(), "--file".into(), file.into()],
+        None => vec!["create".into()],
+    };
+    args.extend(target.args());
+    if file.is_none()
+        && let Some(env) = env
+    {
+        for channel in &env.channels {
+            args.push("-c".into());
+            args.push(channel.clone());
+        }
+        for dependency in &env.dependencies {
+            match dependency {
+                Dependency::Package(spec) => args.push(spec.clone()),
+                // Installed by pip_args() afterwards
+                Dependency::Pip { .. } => {}
+                Dependency::Other(value) => {
+                    warn!("Ignoring unsupported dependency {:?}", value)
+                }
+            }
+        }
+        if !pip_packages(env).is_empty() && !has_package(env, "pip") {
+            debug!("Adding pip to the environment for the pip dependencies");
+            args.push("pip".into());
+        }
+    }
+    args.push("--yes".into());
+    args
+}
+
+/// Whether the conda dependencies in `env` include the package `name`, in any
+/// version.
+fn has_package(env: &RobotmkEnv, name: &str) -> bool {
+    env.dependencies.iter().any(|dependency| match dependency {
+        Dependency::Package(spec) => {
+            spec.split(|c: char| "=<>!~ [".contains(c)).next() == Some(name)
+        }
+        _ => false,
+    })
+}
+
+/// The `pip` dependencies of `env`.
+fn pip_packages(env: &RobotmkEnv) -> Vec<&String> {
+    env.dependencies
+        .iter()
+        .flat_map(|dependency| match dependency {
+            Dependency::Pip { pip } => pip.as_slice(),
+            _ => &[],
+        })
+        .collect()
+}
+
+/// Build the micromamba arguments to pip install the `pip` dependencies of
+/// `env` into the environment `target`, or `None` if it has none.
+fn pip_args(target: &EnvTarget, env: &RobotmkEnv) -> Option<Vec<String>> {
+    let packages = pip_packages(env);
+    if packages.is_empty() {
+        return None;
+    }
+    let mut args: Vec<String> = vec!["run".into()];
+    args.extend(target.args());
+    args.extend(["pip".into(), "install".into()]);
+    args.extend(packages.into_iter().cloned());
+    Some(args)
+}
+
+/// Write the manifest of the environment `target`, just created from `spec`,
+/// to `path`. If `spec` was exported from the environment `from_env`, that is
+/// recorded as the spec file, since the export is gone afterwards.
+fn write_manifest(
+    config: &Config,
+    path: &Path,
+    target: &EnvTarget,
+    spec: Option<&Path>,
+    from_env: Option<&str>,
+) -> Result<(), EnvError> {
+    let manifest_error = |e: std::io::Error| EnvError::Manifest(path.to_path_buf(), e);
+    let spec_contents = spec
+        .map(std::fs::read_to_string)
+        .transpose()
+        .map_err(manifest_error)?;
+    // Whatever else is in it, the spec file is the authority on the channels.
+    let channels = spec_contents
+        .as_deref()
+        .and_then(|contents| serde_yaml_ng::from_str::<RobotmkEnv>(contents).ok())
+        .map(|env| env.channels)
+        .unwrap_or_default();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (env_name, prefix) = match target {
+        EnvTarget::Name(name) => (Some(name.clone()), None),
+        EnvTarget::Prefix(prefix) => (None, Some(prefix.display().to_string())),
+    };
+    let manifest = Json::object([
+        ("env_name", Json::from(env_name)),
+        ("prefix", Json::from(prefix)),
+        (
+            "spec_file",
+            Json::from(
+                from_env
+                    .map(String::from)
+                    .or_else(|| spec.map(|spec| spec.display().to_string())),
+            ),
+        ),
+        (
+            "spec_sha256",
+            Json::from(spec_contents.map(|contents| sha256_hex(contents.as_bytes()))),
+        ),
+        (
+            "channels",
+            Json::Array(channels.into_iter().map(Json::from).collect()),
+        ),
+        (
+            "micromamba_version",
+            Json::from(resolve_micromamba(config).map(|(_, version)| version)),
+        ),
+        ("timestamp", Json::Number(timestamp as i64)),
+    ]);
+    if config.noop_mode {
+        info!("Would write the manifest {}: {}", path.display(), manifest);
+        return Ok(());
+    }
+    write_atomically(path, &format!("{}\n", manifest)).map_err(manifest_error)
+}
+
+/// The environment a command acts on.
+#[derive(Debug, PartialEq)]
+enum EnvTarget {
+    /// An environment in the root prefix, by name
+    Name(String),
+    /// The environment at this (absolute) path, wherever it is
+    Prefix(PathBuf),
+}
+
+impl EnvTarget {
+    /// `prefix` if given, made absolute so that it doesn't depend on the
+    /// directory micromamba runs in. Otherwise `name`, determined the same way
+    /// as by [`determine_env_name()`].
+    fn resolve(
+        config: &Config,
+        name: Option<String>,
+        prefix: Option<PathBuf>,
+        dir: &Path,
+    ) -> Result<Self, EnvError> {
+        match prefix {
+            Some(prefix) => Ok(Self::Prefix(std::path::absolute(&prefix).unwrap_or(prefix))),
+            None => determine_env_name(config, name, dir).map(Self::Name),
+        }
+    }
+
+    /// The micromamba arguments selecting this environment.
+    fn args(&self) -> [String; 2] {
+        match self {
+            Self::Name(name) => ["--name".into(), name.clone()],
+            Self::Prefix(prefix) => ["--prefix".into(), prefix.display().to_string()],
+        }
+    }
+
+    /// Whether the environment exists already.
+    fn exists(&self, config: &Config) -> Result<bool, EnvError> {
+        match self {
+            Self::Name(name) => Ok(existing_env_names(config)?.contains(name)),
+            Self::Prefix(prefix) => Ok(prefix.join("conda-meta").is_dir()),
+        }
+    }
+}
+
+impl fmt::Display for EnvTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{}", name),
+            Self::Prefix(prefix) => write!(f, "{}", prefix.display()),
+        }
+    }
+}
+
+/// Get ready to change environments in the root prefix: make sure that it can
+/// be changed at all, then lock it against other csm processes until the
+/// returned file is dropped.
+fn prepare_changes(config: &Config) -> Result<Option<File>, EnvError> {
+    check_root_prefix_writable(config).map_err(EnvError::RootPrefixNotWritable)?;
+    lock_root_prefix(config).map_err(EnvError::Lock)
+}
+
+/// How long to wait before running micromamba again after it failed.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Run micromamba with `args`, and if it fails, again up to `retries` times.
+/// Only failures of micromamba itself are retried, not failing to run it.
+fn micromamba_with_retries(config: &Config, args: &[String], retries: u32) -> MicromambaResult {
+    let mut attempt = 0;
+    loop {
+        let result = micromamba(config, args.iter().map(String::as_str).collect());
+        match result {
+            MicromambaResult::Ok(status) if !status.success() && attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "micromamba failed ({}), retrying (attempt {} of {})",
+                    status, attempt, retries
+                );
+                std::thread::sleep(RETRY_DELAY * attempt);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Run `action` for each of `env_names`, stopping at the first failure unless
+/// `keep_going` is set. With `keep_going`, every failure is logged, and the
+/// overall result is a failure if any of them failed.
+fn for_each_env<F>(env_names: &[String], keep_going: bool, action: F) -> Result<(), EnvError>
+where
+    F: Fn(&str) -> Result<(), EnvError>,
+{
+    let mut failed = 0;
+    for env_name in env_names {
+        match action(env_name) {
+            Ok(()) => {}
+            Err(e) if keep_going => {
+                error!("{}: {}", env_name, e);
+                failed += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if failed > 0 {
+        return Err(EnvError::SomeFailed(failed, env_names.len()));
+    }
+    Ok(())
+}
+
+/// Remove every environment whose name matches the glob `pattern`. They are
+/// all shown first, and unless `yes` is set or in no-op mode, the user has to
+/// confirm removing them.
+fn remove_matching(
+    config: &Config,
+    pattern: &str,
+    yes: bool,
+    keep_going: bool,
+) -> Result<(), EnvError> {
+    let env_names: Vec<String> = existing_env_names(config)?
+        .into_iter()
+        .filter(|env_name| glob_match(pattern, env_name))
+        .collect();
+    if env_names.is_empty() {
+        info!("No environments match {}", pattern);
+        return Ok(());
+    }
+    println!("Environments matching {}:", pattern);
+    for env_name in &env_names {
+        println!("  {}", env_name);
+    }
+    if !yes && !config.noop_mode && !confirm(&format!("Remove {} environments?", env_names.len())) {
+        return Err(EnvError::NotConfirmed(format!(
+            "removing {} environments",
+            env_names.len()
+        )));
+    }
+    for_each_env(&env_names, keep_going, |env_name| {
+        check(micromamba(
+            config,
+            vec!["env", "remove", "--name", env_name, "--yes"],
+        ))
+    })
+}
+
+/// Rename the environment `old` to `new`. micromamba can't do that directly,
+/// so `new` is made as a clone of `old`, and `old` is only removed once that
+/// worked.
+fn rename_env(config: &Config, old: &str, new: &str, yes: bool) -> Result<(), EnvError> {
+    let existing = existing_env_names(config)?;
+    if !existing.iter().any(|name| name == old) {
+        return Err(EnvError::DoesNotExist(old.to_string()));
+    }
+    if existing.iter().any(|name| name == new) {
+        return Err(EnvError::AlreadyExists(new.to_string()));
+    }
+    if !yes && !config.noop_mode && !confirm(&format!("Rename '{}' to '{}'?", old, new)) {
+        return Err(EnvError::NotConfirmed(format!(
+            "renaming '{}' to '{}'",
+            old, new
+        )));
+    }
+    check(micromamba(
+        config,
+        vec!["create", "--name", new, "--clone", old, "--yes"],
+    ))?;
+    check(micromamba(
+        config,
+        vec!["env", "remove", "--name", old, "--yes"],
+    ))
+    .inspect_err(|_| error!("'{}' was cloned to '{}', but not removed", old, new))
+}
+
+/// A directory of ours in the temporary directory, which only we can access.
+/// It is removed with everything in it when this is dropped.
+struct PrivateTempDir(PathBuf);
+
+impl PrivateTempDir {
+    /// Create a new directory whose name starts with `prefix`. If something of
+    /// that name exists already, e.g. planted by another user, this fails
+    /// rather than using it.
+    fn create(prefix: &str) -> std::io::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let path =
+            std::env::temp_dir().join(format!("{}-{}-{}", prefix, std::process::id(), nanos));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PrivateTempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Export the packages which were explicitly asked for in the environment
+/// `env_name` to a temporary environment file, for `micromamba create -f`.
+/// Return the file, and the directory it is in, which removes it when dropped.
+///
+/// Rather than piping one micromamba into the other, we wait for the export to
+/// finish, so that if it fails, we notice before anything is created.
+fn export_from_history(
+    config: &Config,
+    env_name: &str,
+) -> Result<(PrivateTempDir, PathBuf), EnvError> {
+    let existing = existing_env_names(config)?;
+    if !existing.iter().any(|name| name == env_name) {
+        return Err(EnvError::DoesNotExist(env_name.to_string()));
+    }
+    let export_args = vec!["env", "export", "--name", env_name, "--from-history"];
+    let output = match micromamba_output(config, export_args) {
+        Ok(output) => output,
+        Err(result) => {
+            check(result)?;
+            return Err(EnvError::UnexpectedOutput("micromamba env export".into()));
+        }
+    };
+    check(MicromambaResult::Ok(output.status))?;
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Err(EnvError::UnexpectedOutput(
+            "micromamba env export: it was empty".into(),
+        ));
+    }
+    let dir = PrivateTempDir::create("csm-export").map_err(EnvError::Export)?;
+    // micromamba tells the kind of environment file by its extension.
+    let path = dir.0.join("environment.yaml");
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&output.stdout))
+        .map_err(EnvError::Export)?;
+    debug!("Exported '{}' to {}", env_name, path.display());
+    Ok((dir, path))
+}
+
+/// Ask the user `question`, and return whether they answered yes. If stdin is
+/// not a terminal, nobody can answer, which counts as no.
+fn confirm(question: &str) -> bool {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return false;
+    }
+    print!("{} [y/N] ", question);
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    if stdin.read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// The archive formats `env unpack` can extract, with the system's `tar`.
+const ARCHIVE_EXTENSIONS: &[&str] = &[".tar.gz", ".tgz", ".tar.bz2", ".tar.xz", ".tar"];
+
+/// Return the name of the archive `path` without its extension, or `None` if
+/// it isn't one of the [`ARCHIVE_EXTENSIONS`].
+fn archive_stem(path: &Path) -> Option<&str> {
+    let file_name = path.file_name()?.to_str()?;
+    ARCHIVE_EXTENSIONS
+        .iter()
+        .find_map(|ext| file_name.strip_suffix(ext))
+        .filter(|stem| !stem.is_empty())
+}
+
+/// Make sure the environment name `name` is a single directory name, which
+/// stays inside the directory it is joined to.
+fn check_dir_name(name: &str) -> Result<(), EnvError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(()),
+        _ => Err(EnvError::InvalidName(name.to_string())),
+    }
+}
+
+/// Unpack `args.input` into the `envs` directory of the root prefix, and make
+/// it usable there with conda-unpack.
+fn unpack(config: &Config, args: UnpackArgs) -> Result<(), EnvError> {
+    let Some(stem) = archive_stem(&args.input) else {
+        return Err(EnvError::UnpackFailed(format!(
+            "{} is not one of {}",
+            args.input.display(),
+            ARCHIVE_EXTENSIONS.join(", ")
+        )));
+    };
+    if !args.input.is_file() {
+        return Err(EnvError::UnpackFailed(format!(
+            "{} does not exist",
+            args.input.display()
+        )));
+    }
+    let env_name = add_name_suffix(config, args.name.unwrap_or_else(|| stem.to_string()))?;
+    // The name becomes a directory which --force removes, so it must not lead
+    // anywhere else.
+    check_dir_name(&env_name)?;
+    let Some(root_prefix) = effective_root_prefix(config) else {
+        return Err(EnvError::UnpackFailed(
+            "the root prefix is unknown, set mamba_root_prefix or $MAMBA_ROOT_PREFIX".into(),
+        ));
+    };
+    let target = root_prefix.join("envs").join(&env_name);
+
+    if target.exists() && !args.force {
+        return Err(EnvError::AlreadyExists(env_name));
+    }
+    let mut tar = Command::new("tar");
+    tar.arg("-xf").arg(&args.input).arg("-C").arg(&target);
+    if config.noop_mode {
+        info!(
+            "Would unpack {} into {}",
+            args.input.display(),
+            target.display()
+        );
+    } else {
+        let prepare = || -> std::io::Result<()> {
+            if target.exists() {
+                std::fs::remove_dir_all(&target)?;
+            }
+            std::fs::create_dir_all(&target)
+        };
+        // Make sure the archive is intact before replacing anything with it.
+        let listed = Command::new("tar")
+            .arg("-tf")
+            .arg(&args.input)
+            .stdout(Stdio::null())
+            .status();
+        if !listed.is_ok_and(|status| status.success()) {
+            return Err(EnvError::UnpackFailed(format!(
+                "{} is not a valid archive",
+                args.input.display()
+            )));
+        }
+        prepare().map_err(|e| EnvError::UnpackFailed(format!("{}: {}", target.display(), e)))?;
+        debug!("About to run: {:?}", tar);
+        match tar.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => return Err(EnvError::UnpackFailed(format!("tar failed: {}", status))),
+            Err(e) => return Err(EnvError::UnpackFailed(format!("could not run tar: {}", e))),
+        }
+    }
+
+    // Fix up the paths in the environment, which still point to where it was
+    // packed.
+    let target = target.to_string_lossy();
+    check(micromamba(
+        config,
+        vec!["run", "--prefix", &target, "conda-unpack"],
+    ))
+}
+
+/// Build the micromamba arguments to update the environment `env_name`: from
+/// the environment file `spec` if given, otherwise to the latest versions of
+/// all of its packages.
+fn update_args(env_name: &str, spec: Option<&Path>) -> Vec<String> {
+    let mut args: Vec<String> = match spec {
+        Some(spec) => vec![
+            "install".into(),
+            "--file".into(),
+            spec.display().to_string(),
+        ],
+        None => vec!["update".into(), "--all".into()],
+    };
+    args.extend(["--name".into(), env_name.into(), "--yes".into()]);
+    args
+}
+
+pub fn run(config: Config, subcommand: Subcommand) -> Result<(), EnvError> {
     match subcommand {
         Subcommand::Create(args) => {
-            let Some(env_name) = determine_env_name(args) else {
-                error!("No environment name could be determined. You can specify one with --name");
-                return ExitCode::FAILURE;
+            let dir = Path::new(&args.path);
+            let robotmk_env = match parse_robotmk_env_yaml(&config, dir) {
+                // Say why the name can't be taken from robotmk-env.yaml.
+                Err(e) if args.name_from == NameSource::Yaml && args.prefix.is_none() => {
+                    return Err(e);
+                }
+                robotmk_env => robotmk_env,
+            };
+            let target = match args.prefix {
+                Some(prefix) => EnvTarget::resolve(&config, None, Some(prefix), dir)?,
+                None => {
+                    let name = resolve_env_name_in(&config, args.name_from, args.name, dir)?
+                        .ok_or(EnvError::NameUnresolvable)?;
+                    EnvTarget::Name(add_name_suffix(&config, name)?)
+                }
+            };
+            let from_env = args
+                .from_env
+                .map(|name| add_name_suffix(&config, name))
+                .transpose()?;
+            let exported = match &from_env {
+                Some(env_name) => Some(export_from_history(&config, env_name)?),
+                None => None,
             };
-            let result = micromamba(
+            let file = match &exported {
+                Some((_, path)) => Some(path.to_string_lossy().into_owned()),
+                None => args.file,
+            };
+            // Only when we build the environment from robotmk-env.yaml ourselves
+            // does it matter that we couldn't parse it.
+            let robotmk_env = match (&file, robotmk_env) {
+                (Some(_), _) => None,
+                (None, Ok(env)) => Some(env),
+                (None, Err(EnvError::YamlRead(e))) if e.kind() == ErrorKind::NotFound => None,
+                (None, Err(e)) => return Err(e),
+            };
+            let mut create_args = create_args(&target, file.as_deref(), robotmk_env.as_ref());
+            if let Some(platform) = args.platform {
+                create_args.extend(["--platform".to_string(), platform]);
+            }
+            let channel_priority = args
+                .channel_priority
+                .or(robotmk_env.as_ref().and_then(|env| env.channel_priority));
+            if let Some(channel_priority) = channel_priority {
+                create_args.extend(channel_priority.args().iter().map(|arg| arg.to_string()));
+            }
+            if args.solver_offline {
+                create_args.push("--offline".to_string());
+            }
+            if args.print_cmd {
+                let cmd = micromamba_at(
+                    "micromamba",
+                    &config,
+                    create_args.iter().map(String::as_str).collect(),
+                );
+                println!("{}", shell_command_line(&cmd));
+                return Ok(());
+            }
+            let _lock = prepare_changes(&config)?;
+            if !args.force && target.exists(&config)? {
+                if args.if_missing {
+                    info!("Environment '{}' exists already, skipping", target);
+                    return Ok(());
+                }
+                return Err(EnvError::AlreadyExists(target.to_string()));
+            }
+            let result = micromamba_with_retries(&config, &create_args, args.retries);
+            match result {
+                MicromambaResult::Ok(status) if !status.success() && args.offline_fallback => {
+                    warn!(
+                        "Creating the environment failed ({}), retrying offline from the package cache",
+                        status
+                    );
+                    create_args.push("--offline".to_string());
+                    check(micromamba_with_retries(&config, &create_args, args.retries))?;
+                }
+                result => check(result)?,
+            }
+            // micromamba create can't install pip packages itself.
+            if let Some(pip_args) = robotmk_env.as_ref().and_then(|env| pip_args(&target, env)) {
+                info!("Installing the pip dependencies into '{}'", target);
+                check(micromamba_with_retries(&config, &pip_args, args.retries))?;
+            }
+            if let Some(post_create) = args.post_create.or_else(|| config.post_create.clone()) {
+                info!("Running '{}' in '{}'", post_create, target);
+                let target_args = target.args();
+                let mut run_args = vec!["run", &target_args[0], &target_args[1]];
+                run_args.extend(post_create.split_whitespace());
+                check(micromamba(&config, run_args))?;
+            }
+            if let Some(manifest) = &args.manifest {
+                let spec = match &file {
+                    Some(file) => Some(PathBuf::from(file)),
+                    None => env_file_path(&config, dir),
+                };
+                write_manifest(
+                    &config,
+                    manifest,
+                    &target,
+                    spec.as_deref(),
+                    from_env.as_deref(),
+                )?;
+            }
+            Ok(())
+        }
+        Subcommand::Run(args) => {
+            let target =
+                EnvTarget::resolve(&config, args.name, args.prefix, Path::new(&args.path))?;
+            if let Some(cwd) = &args.cwd
+                && !cwd.is_dir()
+            {
+                return Err(EnvError::NotADirectory(cwd.clone()));
+            }
+            let mut config = config;
+            if let Some(env_file) = &args.env_file {
+                config.extra_env = read_env_file(env_file).map_err(EnvError::EnvFile)?;
+            }
+            let target_args = target.args();
+            let mut run_args = vec!["run", &target_args[0], &target_args[1]];
+            run_args.extend(args.command.iter().map(String::as_str));
+            check(micromamba_in(&config, run_args, args.cwd.as_deref()))
+        }
+        Subcommand::Diff(args) => {
+            let a = add_name_suffix(&config, args.a)?;
+            let b = add_name_suffix(&config, args.b)?;
+            let existing = existing_env_names(&config)?;
+            for name in [&a, &b] {
+                if !existing.contains(name) {
+                    return Err(EnvError::DoesNotExist(name.clone()));
+                }
+            }
+            let a = package_versions(&config, &a)?;
+            let b = package_versions(&config, &b)?;
+            let diff = diff_packages(&a, &b);
+            if args.json {
+                let objects = diff
+                    .into_iter()
+                    .map(|(name, a, b)| {
+                        Json::object([
+                            ("name", Json::from(name)),
+                            ("a", Json::from(a)),
+                            ("b", Json::from(b)),
+                        ])
+                    })
+                    .collect();
+                println!("{}", Json::Array(objects));
+            } else {
+                for (name, a, b) in diff {
+                    println!("{}\t{}\t{}", name, a.unwrap_or("-"), b.unwrap_or("-"));
+                }
+            }
+            Ok(())
+        }
+        Subcommand::Remove(args) => {
+            let _lock = prepare_changes(&config)?;
+            if let Some(pattern) = args.pattern {
+                let pattern = add_name_suffix(&config, pattern)?;
+                return remove_matching(&config, &pattern, args.yes, args.keep_going);
+            }
+            let target =
+                EnvTarget::resolve(&config, args.name, args.prefix, Path::new(&args.path))?;
+            let target_args = target.args();
+            check(micromamba(
                 &config,
-                vec![
-                    "env",
-                    "create",
-                    "--file",
-                    "robotmk-env.yaml",
-                    "--name",
-                    &env_name,
-                    "--yes",
-                ],
-            );
-            result.exit_code()
+                vec!["env", "remove", &target_args[0], &target_args[1], "--yes"],
+            ))
+        }
+        Subcommand::Install(args) => {
+            let _lock = prepare_changes(&config)?;
+            let env_name = determine_env_name(&config, args.name, Path::new(&args.path))?;
+            if !existing_env_names(&config)?.contains(&env_name) {
+                return Err(EnvError::DoesNotExist(env_name));
+            }
+            let mut install_args = vec!["install", "--name", &env_name];
+            for channel in &args.channels {
+                install_args.extend(["-c", channel]);
+            }
+            install_args.extend(args.packages.iter().map(String::as_str));
+            install_args.push("--yes");
+            check(micromamba(&config, install_args))
+        }
+        Subcommand::Rename(args) => {
+            let _lock = prepare_changes(&config)?;
+            let old = add_name_suffix(&config, args.old)?;
+            let new = add_name_suffix(&config, args.new)?;
+            rename_env(&config, &old, &new, args.yes)
+        }
+        Subcommand::Update(args) => {
+            let _lock = prepare_changes(&config)?;
+            let dir = Path::new(&args.path);
+            let spec = env_file_path(&config, dir);
+            let project_env = match (&spec, args.name.is_empty()) {
+                (None, false) => None,
+                _ => Some(determine_env_name(&config, None, dir)?),
+            };
+            let env_names: Vec<String> = if args.name.is_empty() {
+                project_env.iter().cloned().collect()
+            } else {
+                args.name
+                    .into_iter()
+                    .map(|name| add_name_suffix(&config, name))
+                    .collect::<Result<_, _>>()?
+            };
+            for_each_env(&env_names, args.keep_going, |env_name| {
+                // The project's environment file is only for its own
+                // environment, not for others named alongside it.
+                let spec = spec
+                    .as_deref()
+                    .filter(|_| project_env.as_deref() == Some(env_name));
+                let update_args = update_args(env_name, spec);
+                check(micromamba_with_retries(&config, &update_args, args.retries))
+            })
+        }
+        Subcommand::Pack(args) => {
+            let env_name = determine_env_name(&config, args.name, Path::new(&args.path))?;
+            // conda-pack runs inside the environment, so it has to be installed
+            // there.
+            let has_conda_pack = match micromamba_output(
+                &config,
+                vec!["run", "--name", &env_name, "conda-pack", "--version"],
+            ) {
+                Ok(output) => output.status.success(),
+                Err(result) => check(result).map(|()| true)?,
+            };
+            // Packing only reads the environment, so only installing needs the
+            // root prefix to be writable and locked.
+            if !has_conda_pack {
+                let _lock = prepare_changes(&config)?;
+                info!("Installing conda-pack into '{}'", env_name);
+                check(micromamba(
+                    &config,
+                    vec!["install", "--name", &env_name, "conda-pack", "--yes"],
+                ))?;
+            }
+            let mut pack_args = vec!["run", "--name", &env_name, "conda-pack", "--output"];
+            pack_args.push(&args.output);
+            if args.force {
+                pack_args.push("--force");
+            }
+            check(micromamba(&config, pack_args))
+        }
+        Subcommand::Unpack(args) => {
+            let _lock = prepare_changes(&config)?;
+            unpack(&config, args)
+        }
+        Subcommand::List(args) => {
+            let envs = list_envs(&config)?;
+            let counts = if args.counts {
+                package_counts(&config, &envs)
+            } else {
+                vec![None; envs.len()]
+            };
+            if args.json {
+                let objects = envs
+                    .iter()
+                    .zip(&counts)
+                    .map(|(env, count)| {
+                        let mut members = vec![
+                            ("name", Json::from(env.name.clone())),
+                            ("prefix", Json::from(env.prefix.display().to_string())),
+                        ];
+                        if args.counts {
+                            members.push((
+                                "package_count",
+                                (*count).map_or(Json::Null, |n| Json::Number(n as i64)),
+                            ));
+                        }
+                        Json::object(members)
+                    })
+                    .collect();
+                println!("{}", Json::Array(objects));
+            } else {
+                for (env, count) in envs.iter().zip(&counts) {
+                    let name = env.name.as_deref().unwrap_or("-");
+                    match count {
+                        Some(n) => println!("{}\t{}\t{}", name, env.prefix.display(), n),
+                        None => println!("{}\t{}", name, env.prefix.display()),
+                    }
+                }
+            }
+            Ok(())
         }
         _ => {
-            println!("{:?}", config);
-            println!("{:?}", subcommand);
-            ExitCode::SUCCESS
+            debug!("config: {:?}", config);
+            warn!("Not implemented yet: {:?}", subcommand);
+            Ok(())
         }
     }
 }
@@ -156,23 +1844,39 @@ mod tests {
 
     #[test]
     fn test_determine_env_name_with_cli_arg() {
-        let args = CreateArgs {
-            name: Some("test-env".to_string()),
-        };
+        let result = determine_env_name(
+            &Config::default(),
+            Some("test-env".to_string()),
+            Path::new("."),
+        );
+        assert_eq!(result.unwrap(), "test-env");
+    }
 
-        let result = determine_env_name(args);
-        assert_eq!(result, Some("test-env".to_string()));
+    #[test]
+    fn test_add_name_suffix() {
+        let mut config = Config::default();
+        assert_eq!(add_name_suffix(&config, "robot".into()).unwrap(), "robot");
+        config.env_name_suffix = Some("web01".into());
+        assert_eq!(
+            add_name_suffix(&config, "robot".into()).unwrap(),
+            "robot-web01"
+        );
+        config.env_name_suffix = Some("${CSM_TEST_UNSET_SUFFIX}".into());
+        assert!(matches!(
+            add_name_suffix(&config, "robot".into()),
+            Err(EnvError::SuffixVariableUnset(var)) if var == "CSM_TEST_UNSET_SUFFIX"
+        ));
     }
 
     #[test]
     fn test_determine_env_name_cli_arg_overrides_yaml() {
         run_in_temp_dir("csm_test_override", Some("name: yaml-env-name"), || {
-            let args = CreateArgs {
-                name: Some("cli-override".to_string()),
-            };
-
-            let result = determine_env_name(args);
-            assert_eq!(result, Some("cli-override".to_string()));
+            let result = determine_env_name(
+                &Config::default(),
+                Some("cli-override".to_string()),
+                Path::new("."),
+            );
+            assert_eq!(result.unwrap(), "cli-override");
         });
     }
 
@@ -190,20 +1894,347 @@ mod tests {
                 Some("other_field: value\nyet_another: field"),
                 "yaml_no_name",
             ),
+            // Only the name matters for the name.
+            (
+                "invalid_field",
+                Some("name: robot-web\nchannel_priority: strikt"),
+                "robot-web",
+            ),
             (
-                "invalid_yaml",
-                Some("invalid: yaml: content: \"unclosed"),
-                "invalid_yaml",
+                "invalid_metadata",
+                Some("name: robot-web\nrobotmk: web"),
+                "robot-web",
             ),
+            ("empty_yaml", Some(""), "empty_yaml"),
             ("no_yaml", None, "no_yaml"),
         ];
 
         for (dir_name, yaml, expected) in test_cases {
             run_in_temp_dir(dir_name, yaml, || {
-                let args = CreateArgs { name: None };
-                let result = determine_env_name(args);
+                let result = determine_env_name(&Config::default(), None, Path::new("."));
                 assert_eq!(result.unwrap(), expected, "Failed case: {}", dir_name);
             });
         }
+
+        // Not falling back to the directory name, which may well be another
+        // environment's.
+        run_in_temp_dir(
+            "invalid_yaml",
+            Some("invalid: yaml: content: \"unclosed"),
+            || {
+                assert!(matches!(
+                    determine_env_name(&Config::default(), None, Path::new(".")),
+                    Err(EnvError::YamlParse(_))
+                ));
+                assert_eq!(
+                    determine_env_name(&Config::default(), Some("cli".into()), Path::new("."))
+                        .unwrap(),
+                    "cli"
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn test_resolve_env_name_expands_variables() {
+        let yaml_name = Some("robot-${CSM_TEST_SITE}-%CSM_TEST_SITE%");
+        let dir = Path::new("/projects/fallback");
+        let resolve = |name| resolve_env_name_from(NameSource::Auto, name, yaml_name, dir);
+
+        // The variable is never set in the unit tests; the integration tests
+        // cover expanding it, since setting it here would race with the
+        // other tests.
+        assert!(matches!(
+            resolve(None),
+            Err(EnvError::NameVariableUnset(var)) if var == "CSM_TEST_SITE"
+        ));
+        // An explicit name doesn't need the variable.
+        assert_eq!(
+            resolve(Some("cli".into())).unwrap(),
+            Some("cli".to_string())
+        );
+    }
+
+    #[test]
+    fn test_determine_env_name_from_other_dir() {
+        // Run in an unrelated directory, so the project directory is not cwd
+        run_in_temp_dir("csm_test_other_cwd", None, || {
+            let project = env::temp_dir().join("csm_test_project_dir");
+            fs::create_dir_all(project.join("sub")).unwrap();
+
+            assert_eq!(
+                determine_env_name(&Config::default(), None, &project).unwrap(),
+                "csm_test_project_dir"
+            );
+            assert_eq!(
+                determine_env_name(&Config::default(), None, &project.join("sub").join(".."))
+                    .unwrap(),
+                "csm_test_project_dir"
+            );
+
+            fs::write(project.join("robotmk-env.yaml"), "name: from-yaml").unwrap();
+            assert_eq!(
+                determine_env_name(&Config::default(), None, &project).unwrap(),
+                "from-yaml"
+            );
+
+            fs::remove_dir_all(&project).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_parse_robotmk_env_yaml_errors() {
+        run_in_temp_dir("csm_test_yaml_errors", None, || {
+            assert!(matches!(
+                parse_robotmk_env_yaml(&Config::default(), Path::new(".")),
+                Err(EnvError::YamlRead(e)) if e.kind() == ErrorKind::NotFound
+            ));
+        });
+        run_in_temp_dir(
+            "csm_test_yaml_errors",
+            Some("invalid: yaml: content: \"unclosed"),
+            || {
+                assert!(matches!(
+                    parse_robotmk_env_yaml(&Config::default(), Path::new(".")),
+                    Err(EnvError::YamlParse(_))
+                ))
+            },
+        );
+    }
+
+    #[test]
+    fn test_env_file_names() {
+        run_in_temp_dir("csm_test_env_file_names", None, || {
+            let dir = Path::new(".");
+            let config = Config {
+                env_file_names: vec!["environment.yml".into(), "robotmk-env.yaml".into()],
+                ..Config::default()
+            };
+            fs::write("robotmk-env.yml", "name: from-yml").unwrap();
+            assert_eq!(
+                determine_env_name(&Config::default(), None, dir).unwrap(),
+                "from-yml"
+            );
+            // Not one of the names in this config
+            assert_eq!(env_file_path(&config, dir), None);
+
+            fs::write("robotmk-env.yaml", "name: from-yaml").unwrap();
+            fs::write("environment.yml", "name: from-environment").unwrap();
+            assert_eq!(
+                determine_env_name(&config, None, dir).unwrap(),
+                "from-environment"
+            );
+            assert_eq!(
+                determine_env_name(&Config::default(), None, dir).unwrap(),
+                "from-yaml"
+            );
+        });
+    }
+
+    #[test]
+    fn test_robotmk_metadata() {
+        let env: RobotmkEnv =
+            serde_yaml_ng::from_str("robotmk:\n  category: web\n  owner: team-x\n").unwrap();
+        let metadata = env.robotmk.unwrap();
+        assert_eq!(metadata.category.as_deref(), Some("web"));
+        assert_eq!(
+            metadata.to_json().to_string(),
+            r#"{"category":"web","build_tag":null}"#
+        );
+
+        assert!(serde_yaml_ng::from_str::<RobotmkEnv>("robotmk: [web]").is_err());
+    }
+
+    #[test]
+    fn test_diff_packages() {
+        let versions = |packages: &[(&str, &str)]| -> BTreeMap<String, String> {
+            packages
+                .iter()
+                .map(|(name, version)| (name.to_string(), version.to_string()))
+                .collect()
+        };
+        let a = versions(&[
+            ("python", "3.12.1"),
+            ("robotframework", "7.0"),
+            ("zlib", "1.3"),
+        ]);
+        let b = versions(&[
+            ("python", "3.12.1"),
+            ("robotframework", "7.1"),
+            ("pip", "24.0"),
+        ]);
+        assert_eq!(
+            diff_packages(&a, &b),
+            vec![
+                ("pip", None, Some("24.0")),
+                ("robotframework", Some("7.0"), Some("7.1")),
+                ("zlib", Some("1.3"), None),
+            ]
+        );
+        assert!(diff_packages(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let value = serde_yaml_ng::from_str("namme: foo\nchannels: []\n1: x").unwrap();
+        assert_eq!(unknown_keys(&value), vec!["namme", "Number(1)"]);
+
+        let value = serde_yaml_ng::from_str("just a string").unwrap();
+        assert!(unknown_keys(&value).is_empty());
+    }
+
+    #[test]
+    fn test_create_args() {
+        let env: RobotmkEnv = serde_yaml_ng::from_str(
+            "channels: [conda-forge, bioconda]\ndependencies: [python=3.12, {pip: [x]}, robotframework]",
+        )
+        .unwrap();
+
+        assert_eq!(
+            create_args(&EnvTarget::Name("foo".into()), None, Some(&env)),
+            vec![
+                "create",
+                "--name",
+                "foo",
+                "-c",
+                "conda-forge",
+                "-c",
+                "bioconda",
+                "python=3.12",
+                "robotframework",
+                "pip",
+                "--yes"
+            ]
+        );
+        assert_eq!(
+            pip_args(&EnvTarget::Name("foo".into()), &env).unwrap(),
+            vec!["run", "--name", "foo", "pip", "install", "x"]
+        );
+        let env: RobotmkEnv =
+            serde_yaml_ng::from_str("dependencies: [pip>=24, {pip: [x, y==1.0]}]").unwrap();
+        assert_eq!(
+            create_args(&EnvTarget::Name("foo".into()), None, Some(&env)),
+            vec!["create", "--name", "foo", "pip>=24", "--yes"]
+        );
+        assert_eq!(
+            pip_args(&EnvTarget::Name("foo".into()), &env).unwrap(),
+            vec!["run", "--name", "foo", "pip", "install", "x", "y==1.0"]
+        );
+        assert_eq!(
+            create_args(&EnvTarget::Name("foo".into()), None, None),
+            vec!["create", "--name", "foo", "--yes"]
+        );
+        assert_eq!(
+            create_args(
+                &EnvTarget::Name("foo".into()),
+                Some("other.yaml"),
+                Some(&env)
+            ),
+            vec![
+                "env",
+                "create",
+                "--file",
+                "other.yaml",
+                "--name",
+                "foo",
+                "--yes"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_env_name_from() {
+        let env = "from-yaml";
+        let dir = Path::new("/projects/from-dir");
+        let arg = || Some("from-arg".to_string());
+        let resolve = |source, name, env| resolve_env_name_from(source, name, env, dir);
+
+        assert_eq!(
+            resolve(NameSource::Dir, arg(), Some(env))
+                .unwrap()
+                .as_deref(),
+            Some("from-dir")
+        );
+        assert_eq!(
+            resolve(NameSource::Yaml, arg(), Some(env))
+                .unwrap()
+                .as_deref(),
+            Some("from-yaml")
+        );
+        assert_eq!(
+            resolve(NameSource::Arg, arg(), Some(env))
+                .unwrap()
+                .as_deref(),
+            Some("from-arg")
+        );
+        assert!(matches!(
+            resolve(NameSource::Yaml, arg(), None),
+            Err(EnvError::NameSourceEmpty(NameSource::Yaml))
+        ));
+        assert!(matches!(
+            resolve(NameSource::Arg, None, Some(env)),
+            Err(EnvError::NameSourceEmpty(NameSource::Arg))
+        ));
+    }
+
+    #[test]
+    fn test_last_dir_name() {
+        let cases = [
+            ("/home/user/robots/web", Some("web")),
+            ("/home/user/robots/web/", Some("web")),
+            ("/", None),
+            (r"C:\robots\web", Some("web")),
+            (r"C:\robots\web\\", Some("web")),
+            (r"C:\", None),
+            (r"\\server\share", None),
+            (r"\\server\share\", None),
+            (r"\\server\share\robots\web", Some("web")),
+            (r"\\?\C:\robots\web", Some("web")),
+            (r"\\?\C:\", None),
+            (r"\\?\UNC\server\share\", None),
+            (r"\\?\UNC\server\share\web\", Some("web")),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(last_dir_name(path), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_check_dir_name() {
+        assert!(check_dir_name("robot-1.0").is_ok());
+        for name in ["", ".", "..", "/tmp/proj", "a/b", "a\\b", "./a"] {
+            assert!(
+                matches!(check_dir_name(name), Err(EnvError::InvalidName(_))),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_archive_stem() {
+        assert_eq!(archive_stem(Path::new("/tmp/robot.tar.gz")), Some("robot"));
+        assert_eq!(archive_stem(Path::new("robot.v2.tgz")), Some("robot.v2"));
+        assert_eq!(archive_stem(Path::new("robot.zip")), None);
+        assert_eq!(archive_stem(Path::new(".tar")), None);
+    }
+
+    #[test]
+    fn test_update_args() {
+        assert_eq!(
+            update_args("foo", None),
+            vec!["update", "--all", "--name", "foo", "--yes"]
+        );
+        assert_eq!(
+            update_args("foo", Some(Path::new("robotmk-env.yaml"))),
+            vec![
+                "install",
+                "--file",
+                "robotmk-env.yaml",
+                "--name",
+                "foo",
+                "--yes"
+            ]
+        );
     }
 }
@@ -0,0 +1,40 @@
+//! The exit codes of csm. Scripts depend on these, so they must not change.
+//!
+//! Everything which decides how csm exits returns one of these as a `u8`, and
+//! only `main` turns it into the process's exit code.
+//!
+//! When micromamba itself fails, csm exits with micromamba's exit code
+//! instead, which may be any of these too.
+
+/// Everything worked.
+pub const SUCCESS: u8 = 0;
+
+/// Something went wrong in csm, which it logged. This is what
+/// [`std::process::ExitCode::FAILURE`] is.
+pub const FAILURE: u8 = 1;
+
+/// The command line or the configuration in .csmrc is wrong. clap uses this
+/// for usage errors, too.
+pub const CONFIG: u8 = 2;
+
+/// `micromamba` was killed after the timeout, or csm ran past its deadline,
+/// like timeout(1).
+pub const TIMED_OUT: u8 = 124;
+
+/// A `micromamba` was found, but could not be run, like a shell's "command
+/// not executable".
+pub const COULD_NOT_RUN: u8 = 126;
+
+/// No `micromamba` could be found, like a shell's "command not found".
+pub const NOT_FOUND: u8 = 127;
+
+/// The exit codes, for the long --help.
+pub const HELP: &str = "\
+Exit codes:
+  0    Success
+  1    csm failed
+  2    Invalid command line or .csmrc
+  124  micromamba or csm as a whole timed out
+  126  micromamba was found, but could not be run
+  127  micromamba could not be found
+When micromamba runs and fails, csm exits with micromamba's exit code.";
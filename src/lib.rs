@@ -0,0 +1,71 @@
+//! csm - Checkmk synthetic monitoring
+//!
+//! Everything the `csm` command-line tool does is available from this library,
+//! so that other Rust code can use it in-process instead of shelling out to
+//! `csm`. The binary is only a thin wrapper around it which parses the command
+//! line and sets up logging.
+//!
+//! The results of a command, and only those, are printed to stdout, so that
+//! they can be piped into other programs. Everything else (progress, warnings,
+//! errors) goes through the `log` macros and thus to stderr.
+
+pub mod cache;
+pub mod config;
+pub mod csmrc;
+pub mod doctor;
+pub mod env;
+pub mod exit;
+pub mod json;
+pub mod micromamba;
+pub mod robot;
+pub mod status;
+pub mod util;
+
+use crate::csmrc::Config;
+use log::{debug, info};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Run one of the `env` subcommands.
+pub fn run_env(config: Config, subcommand: env::Subcommand) -> Result<(), env::EnvError> {
+    env::run(config, subcommand)
+}
+
+/// Find the `micromamba` csm would use, returning its path and version, or
+/// `None` if there is none which can be run.
+pub fn resolve_micromamba(config: &Config) -> Option<(PathBuf, String)> {
+    micromamba::resolve_micromamba(config)
+}
+
+/// The .mambarc csm manages, in the home directory `home`.
+pub fn mambarc_path(home: &Path) -> PathBuf {
+    home.join(".mambarc")
+}
+
+/// Create a ~/.mambarc (%UserProfile%\.mambarc on Windows) if it does not
+/// exist, from the configured template or the one bundled with csm.
+pub fn create_mambarc(config: &Config, home: &Path) -> std::io::Result<()> {
+    let mambarc = match &config.mambarc_template {
+        Some(template) => std::fs::read_to_string(template)?,
+        None => include_str!("../templates/mambarc").to_string(),
+    };
+    let mambarc_path = mambarc_path(home);
+
+    if config.noop_mode && !mambarc_path.exists() {
+        info!("Would create {}", mambarc_path.display());
+        return Ok(());
+    }
+
+    match File::create_new(&mambarc_path) {
+        Ok(mut file) => file.write_all(mambarc.trim_start().as_bytes())?,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            debug!(
+                "File {} already exists, not creating",
+                mambarc_path.display()
+            )
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
@@ -1,32 +1,119 @@
-mod csmrc;
-mod env;
-mod micromamba;
-mod robot;
-
-use crate::csmrc::Config;
-use clap::{Parser, Subcommand};
-use log::{LevelFilter, debug, error, info, warn};
-use std::fs::File;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use csm::csmrc::Config;
+use csm::json::Json;
+use csm::micromamba::{micromamba_source, remove_ephemeral_cache, set_deadline};
+use csm::util::{homedir, write_atomically};
+use csm::{cache, config, create_mambarc, doctor, env, exit, robot, status};
+use env_logger::WriteStyle;
+use log::{Level, LevelFilter, Log, Metadata, Record, debug, error, warn};
+use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
-#[command(version)]
+#[command(version, after_long_help = exit::HELP)]
 /// Checkmk synthetic monitoring command-line tool
 struct Cli {
-    /// Enable verbose debugging output
-    #[arg(short, long)]
-    verbose: bool,
+    /// Enable verbose debugging output. Give twice for even more (trace) output
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
 
-    /// Don't make any changes, only print what would happen
+    /// Don't make any changes, only print what would happen. Anything which
+    /// only looks, like reading robotmk-env.yaml or asking micromamba which
+    /// environments exist, is still done
     #[arg(short = 'n', long = "noop")]
     noop_mode: bool,
 
+    /// Use this as the micromamba root prefix, overriding both
+    /// `mamba_root_prefix` in .csmrc and $MAMBA_ROOT_PREFIX
+    #[arg(long, value_name = "PATH")]
+    root_prefix: Option<String>,
+
+    /// Don't create ~/.mambarc, even if it doesn't exist. csm then doesn't
+    /// look at it or at the `mambarc_template` at all
+    #[arg(long, visible_alias = "no-default-mambarc")]
+    no_mambarc: bool,
+
+    /// Don't append `env_name_suffix` from .csmrc to environment names
+    #[arg(long)]
+    no_suffix: bool,
+
+    /// Make micromamba read only the ~/.mambarc csm manages, ignoring any
+    /// other rc files, overriding `use_managed_mambarc` in .csmrc
+    #[arg(long)]
+    managed_mambarc: bool,
+
+    /// Kill micromamba if a single run of it takes longer than this, overriding
+    /// `micromamba_timeout` in .csmrc
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Give up if the whole run takes longer than this, killing micromamba if
+    /// it is running, and exit with 124
+    #[arg(long, value_name = "SECONDS")]
+    deadline_secs: Option<u64>,
+
+    /// Use a fresh, temporary cache directory for this run only, instead of
+    /// the usual one, and remove it afterwards. Good for reproducing problems
+    /// without the cache getting in the way
+    #[arg(long)]
+    ephemeral_cache: bool,
+
+    /// When to color the log output. Defaults to auto, unless $NO_COLOR is set
+    #[arg(long, value_name = "WHEN")]
+    color: Option<Color>,
+
+    /// Pass this argument to micromamba every time csm runs it, before the
+    /// arguments for the command, after any `micromamba_args` from .csmrc. Can
+    /// be given more than once
+    #[arg(
+        long = "micromamba-arg",
+        value_name = "ARG",
+        allow_hyphen_values = true
+    )]
+    micromamba_args: Vec<String>,
+
+    /// After running, write the outcome to this file as a JSON object with
+    /// `success`, `exit_code`, `micromamba_source` and `message`
+    #[arg(long, value_name = "PATH")]
+    result_file: Option<PathBuf>,
+
+    /// While running, keep this file up to date as a JSON object with `pid`,
+    /// `phase` (e.g. solving or running) and `timestamp`, rewritten at least
+    /// every 5 seconds, so that a supervisor can tell when csm is stuck
+    #[arg(long, value_name = "PATH")]
+    status_file: Option<PathBuf>,
+
+    /// Also write log messages to this file, appending to it
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// How to format log messages. With json, each message is written as one
+    /// JSON object per line
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    log_format: LogFormat,
+
     #[command(subcommand)]
     command: Command,
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Color {
+    /// Color the output if it goes to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Manipulate Robotmk environments
@@ -36,47 +123,234 @@ enum Command {
     /// Manage Robotmk robots
     #[command(subcommand)]
     Robot(robot::Subcommand),
+
+    /// Inspect csm's cache directory
+    #[command(subcommand)]
+    Cache(cache::Subcommand),
+
+    /// Inspect csm's configuration
+    #[command(subcommand)]
+    Config(config::Subcommand),
+
+    /// Check that csm and its environment are set up correctly
+    Doctor,
+}
+
+impl Cli {
+    /// Apply the command-line flags which override values from .csmrc.
+    fn apply_to(&self, mut config: Config) -> Config {
+        if self.noop_mode {
+            config.noop_mode = true;
+        }
+        if let Some(root_prefix) = &self.root_prefix {
+            config.mamba_root_prefix = Some(root_prefix.clone());
+        }
+        if self.no_mambarc {
+            config.create_mambarc = false;
+        }
+        if self.no_suffix {
+            config.env_name_suffix = None;
+        }
+        if self.managed_mambarc {
+            config.use_managed_mambarc = true;
+        }
+        if let Some(timeout) = self.timeout {
+            config.micromamba_timeout = Some(timeout);
+        }
+        if self.ephemeral_cache {
+            config.ephemeral_cache = true;
+        }
+        config
+            .micromamba_args
+            .extend(self.micromamba_args.iter().cloned());
+        config
+    }
+}
+
+/// Writes log messages both to stderr and to the --log-file.
+struct Tee(File);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _ = std::io::stderr().write_all(buf);
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let _ = std::io::stderr().flush();
+        self.0.flush()
+    }
+}
+
+/// Passes log records on to env_logger, remembering the last error for the
+/// --result-file.
+struct RecordingLogger(env_logger::Logger);
+
+/// The last error logged, if any.
+static LAST_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+impl Log for RecordingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Error
+            && let Ok(mut last_error) = LAST_ERROR.lock()
+        {
+            *last_error = Some(record.args().to_string());
+        }
+        self.0.log(record)
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
 }
 
 fn main() -> ExitCode {
     let cli = Cli::parse();
+    if let Err(code) = init_logging(&cli) {
+        return ExitCode::from(code);
+    }
+    let result_file = cli.result_file.clone();
+    if let Some(status_file) = &cli.status_file {
+        status::start(status_file.clone());
+    }
+    if let Some(secs) = cli.deadline_secs {
+        start_watchdog(Duration::from_secs(secs), result_file.clone());
+    }
+    let code = run(cli);
+    status::set_phase("done");
+    remove_ephemeral_cache();
+    if let Some(result_file) = result_file
+        && let Err(e) = write_result_file(&result_file, code)
+    {
+        error!("Could not write {}: {}", result_file.display(), e);
+    }
+    ExitCode::from(code)
+}
 
-    // Set up logging
-    let default_verbosity = if cli.verbose {
-        LevelFilter::Debug
-    } else {
+/// How long after the deadline the watchdog waits for csm to wind down by
+/// itself, after micromamba was killed.
+const WATCHDOG_GRACE: Duration = Duration::from_secs(5);
+
+/// Make sure csm is done after `duration`: no micromamba may run past it, and
+/// if csm itself is still busy a little later, it exits with
+/// [`exit::TIMED_OUT`].
+fn start_watchdog(duration: Duration, result_file: Option<PathBuf>) {
+    set_deadline(Instant::now() + duration);
+    std::thread::spawn(move || {
+        std::thread::sleep(duration + WATCHDOG_GRACE);
+        error!(
+            "csm did not finish within the deadline of {} seconds",
+            duration.as_secs()
+        );
+        status::set_phase("timed out");
+        remove_ephemeral_cache();
+        if let Some(result_file) = result_file
+            && let Err(e) = write_result_file(&result_file, exit::TIMED_OUT)
+        {
+            error!("Could not write {}: {}", result_file.display(), e);
+        }
+        std::process::exit(exit::TIMED_OUT.into());
+    });
+}
+
+fn init_logging(cli: &Cli) -> Result<(), u8> {
+    let default_verbosity = match cli.verbose {
         // We use info level for no-op mode messages.
-        LevelFilter::Info
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
     };
     let mut env_logger_builder = env_logger::Builder::new();
     env_logger_builder.filter_level(default_verbosity);
     env_logger_builder.parse_default_env();
+    let color = match cli.color {
+        Some(color) => Some(color),
+        // See https://no-color.org: any non-empty value disables color.
+        None if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) => Some(Color::Never),
+        None => None,
+    };
+    if let Some(color) = color {
+        env_logger_builder.write_style(match color {
+            Color::Auto => WriteStyle::Auto,
+            Color::Always => WriteStyle::Always,
+            Color::Never => WriteStyle::Never,
+        });
+    }
     env_logger_builder.format_timestamp(None);
-    env_logger_builder.init();
-
-    let config = match Config::from_csmrc() {
-        Ok(config) => {
-            if cli.noop_mode {
-                Config {
-                    noop_mode: true,
-                    ..config
-                }
-            } else {
-                config
+    if let LogFormat::Json = cli.log_format {
+        env_logger_builder.format(|buf, record| {
+            let object = Json::object([
+                ("timestamp", Json::from(buf.timestamp().to_string())),
+                ("level", Json::from(record.level().as_str())),
+                ("target", Json::from(record.target())),
+                ("message", Json::from(record.args().to_string())),
+            ]);
+            writeln!(buf, "{}", object)
+        });
+    }
+    if let Some(log_file) = &cli.log_file {
+        match OpenOptions::new().create(true).append(true).open(log_file) {
+            Ok(file) => {
+                env_logger_builder.target(env_logger::Target::Pipe(Box::new(Tee(file))));
             }
+            Err(e) => {
+                eprintln!("Could not open log file {}: {}", log_file.display(), e);
+                return Err(exit::FAILURE);
+            }
+        }
+    }
+    let logger = env_logger_builder.build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(RecordingLogger(logger)))
+        .expect("the logger is only set up once");
+    Ok(())
+}
+
+/// Run the command, and return the exit code for it, one of those in [`exit`]
+/// or micromamba's.
+fn run(cli: Cli) -> u8 {
+    // These report on problems with the config rather than bailing out, and
+    // must not have side effects like creating a .mambarc.
+    match &cli.command {
+        Command::Doctor => {
+            return doctor::run(
+                Config::from_csmrc().map(|config| cli.apply_to(config)),
+                cli.apply_to(Config::default()),
+            );
         }
+        Command::Config(sub) => {
+            let config = Config::from_csmrc().map(|config| cli.apply_to(config));
+            return config::run(config, sub.clone());
+        }
+        _ => {}
+    }
+
+    let config = match Config::from_csmrc() {
+        Ok(config) => cli.apply_to(config),
         Err(err) => {
             error!("Failed to parse .csmrc: {}", err);
-            return ExitCode::FAILURE;
+            return exit::CONFIG;
         }
     };
 
-    let Some(home) = dirs::home_dir() else {
+    if let Err(err) = config.validate() {
+        error!("Invalid configuration: {}", err);
+        return exit::CONFIG;
+    }
+
+    let Some(home) = homedir() else {
         error!("Failed to determine home directory");
-        return ExitCode::FAILURE;
+        return exit::FAILURE;
     };
 
-    if let Err(e) = create_mambarc(&config, &home) {
+    if !config.create_mambarc {
+        debug!("Not creating .mambarc, disabled by configuration");
+    } else if let Err(e) = create_mambarc(&config, &home) {
         let attempted_path = home.join(".mambarc");
         warn!(
             "Could not create {}, but continuing: {}",
@@ -86,31 +360,34 @@ fn main() -> ExitCode {
     }
 
     match cli.command {
-        Command::Env(sub) => env::run(config, sub),
+        Command::Env(sub) => match csm::run_env(config, sub) {
+            Ok(()) => exit::SUCCESS,
+            Err(e) => {
+                error!("{}", e);
+                e.exit_code()
+            }
+        },
         Command::Robot(sub) => robot::run(config, sub),
+        Command::Cache(sub) => cache::run(config, sub),
+        Command::Config(_) | Command::Doctor => {
+            unreachable!("handled before loading the config")
+        }
     }
 }
 
-/// Create a ~/.mambarc (%UserProfile%\.mambarc on Windows) if it does not
-/// exist.
-fn create_mambarc(config: &Config, home: &Path) -> std::io::Result<()> {
-    let mambarc = include_str!("../templates/mambarc");
-    let mambarc_path = home.join(".mambarc");
-
-    if config.noop_mode && !mambarc_path.exists() {
-        info!("Would create {}", mambarc_path.display());
-        return Ok(());
-    }
-
-    match File::create_new(&mambarc_path) {
-        Ok(mut file) => file.write_all(mambarc.trim_start().as_bytes())?,
-        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
-            debug!(
-                "File {} already exists, not creating",
-                mambarc_path.display()
-            )
-        }
-        Err(e) => return Err(e),
-    }
-    Ok(())
+/// Write the outcome of this run to `path` as JSON. The file is replaced
+/// atomically, so that readers never see it half written.
+fn write_result_file(path: &Path, code: u8) -> std::io::Result<()> {
+    let success = code == exit::SUCCESS;
+    let message = match LAST_ERROR.lock().ok().and_then(|e| e.clone()) {
+        Some(message) if !success => message,
+        _ => String::new(),
+    };
+    let result = Json::object([
+        ("success", Json::Bool(success)),
+        ("exit_code", Json::Number(code.into())),
+        ("micromamba_source", Json::from(micromamba_source())),
+        ("message", Json::from(message)),
+    ]);
+    write_atomically(path, &format!("{}\n", result))
 }
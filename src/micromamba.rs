@@ -1,59 +1,394 @@
 //! This module deals with `micromamba` - obtaining it, calling it, etc.
 
 use crate::csmrc::Config;
-use log::{debug, error, info};
+use crate::exit;
+use crate::util::{expand_path, free_space, homedir, is_writable};
+use log::{debug, error, info, warn};
 use std::collections::HashMap;
-use std::process::{Command, ExitCode, ExitStatus};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, TryLockError};
+use std::io::{ErrorKind, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// The result from trying to shell out to `micromamba`.
 ///
-/// It would be better if we could "accumulate" errors as we try different
-/// fallbacks to run `micromamba`, something like Result/Either, but with an
-/// accumulating Applicative on the error side, akin to the "validation" package
-/// in Haskell. Alas, this does not seem to exist in Rust, so we drop the errors
-/// as we try to determine a working `micromamba` and just report whether or not
-/// we were able to do so at the end. (Of course, we log along the way in
-/// `micromamba()`.)
+/// When no `micromamba` could be run, we say why for each of the ones we
+/// tried, rather than just that none of them worked.
 pub enum MicromambaResult {
     /// We were run in no-op mode, so we didn't actually call out to it
     Noop,
     /// We were able to successfully call it and get a result
     Ok(ExitStatus),
-    /// We were unable to find or create a working `micromamba`
-    CouldNotRun,
+    /// There is no `micromamba` binary anywhere we looked, for these reasons
+    NotFound(String),
+    /// We found a `micromamba` binary, but could not run it, for these reasons
+    CouldNotRun(String),
+    /// It ran for longer than the configured timeout, so we killed it
+    TimedOut,
+    /// We started it with this process ID, and did not wait for it to finish
+    Spawned(u32),
 }
 
 impl MicromambaResult {
-    pub fn exit_code(&self) -> ExitCode {
+    /// The exit code csm should exit with after running micromamba: its own
+    /// exit code if it ran, otherwise [`exit::NOT_FOUND`], [`exit::COULD_NOT_RUN`]
+    /// or [`exit::TIMED_OUT`].
+    pub fn exit_code(&self) -> u8 {
         match self {
-            Self::Ok(exit_status) => exit_status
-                .code()
-                .map(|c| ExitCode::from(c as u8))
-                .unwrap_or(ExitCode::FAILURE),
-            Self::Noop => ExitCode::SUCCESS,
-            Self::CouldNotRun => ExitCode::FAILURE,
+            Self::Ok(exit_status) => exit_status.code().map(|c| c as u8).unwrap_or(exit::FAILURE),
+            Self::Noop | Self::Spawned(_) => exit::SUCCESS,
+            Self::NotFound(_) => exit::NOT_FOUND,
+            Self::CouldNotRun(_) => exit::COULD_NOT_RUN,
+            Self::TimedOut => exit::TIMED_OUT,
         }
     }
 }
 
+/// Makes sure we only warn once that there is no managed mambarc to pass.
+static MISSING_MAMBARC_WARNING: Once = Once::new();
+
 /// Return a [`Command`] ready to shell out to `micromamba` with the appropriate
 /// environment variables set based on configuration.
-pub fn micromamba_at(path: &str, config: &Config, args: Vec<&str>) -> Command {
+pub fn micromamba_at<P: AsRef<OsStr>>(path: P, config: &Config, args: Vec<&str>) -> Command {
     let mut env_vars: HashMap<&str, String> = HashMap::new();
 
-    if let Some(mamba_root_prefix) = &config.mamba_root_prefix {
-        env_vars.insert("MAMBA_ROOT_PREFIX", mamba_root_prefix.to_string());
+    if let Some(mamba_root_prefix) = root_prefix_path(config) {
+        env_vars.insert(
+            "MAMBA_ROOT_PREFIX",
+            mamba_root_prefix.to_string_lossy().into_owned(),
+        );
     }
 
     let mut cmd = Command::new(path);
+    // micromamba reads only the rc files it is given, if any.
+    if config.use_managed_mambarc
+        && let Some(home) = homedir()
+    {
+        let mambarc = crate::mambarc_path(&home);
+        if mambarc.is_file() {
+            cmd.arg("--rc-file").arg(mambarc);
+        } else {
+            MISSING_MAMBARC_WARNING.call_once(|| {
+                warn!(
+                    "{} does not exist, so micromamba reads its usual rc files instead",
+                    mambarc.display()
+                )
+            });
+        }
+    }
+    cmd.args(&config.micromamba_args);
     cmd.args(args);
     cmd.envs(env_vars);
-    if config.noop_mode {
-        info!("Would run: {:?}", cmd);
+    cmd.envs(config.extra_env.iter().map(|(key, value)| (key, value)));
+    debug!("About to run: {:?}", cmd);
+    cmd
+}
+
+/// The directory in which csm keeps its own files, e.g. its copy of
+/// `micromamba`: `cache_dir` from .csmrc if set, otherwise `csm` in the user's
+/// cache directory. If that can't be written to, e.g. because the home
+/// directory is read-only, a directory of our own in the temporary directory
+/// is used instead.
+///
+/// With `ephemeral_cache`, it is a fresh directory for this run only, which
+/// [`remove_ephemeral_cache()`] removes again.
+pub fn cache_dir_path(config: &Config) -> Option<PathBuf> {
+    if config.ephemeral_cache {
+        return Some(ephemeral_cache_dir().clone());
+    }
+    if let Some(cache_dir) = &config.cache_dir {
+        return Some(expand_path(cache_dir));
+    }
+    DEFAULT_CACHE_DIR
+        .get_or_init(|| default_cache_dir(config))
+        .clone()
+}
+
+/// The cache directory used without `cache_dir` in .csmrc, once
+/// [`default_cache_dir()`] picked it.
+static DEFAULT_CACHE_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Pick the cache directory to use without `cache_dir` in .csmrc. Finding out
+/// whether the user's cache directory is writable means writing a probe file,
+/// so in no-op mode we don't, and assume it is.
+fn default_cache_dir(config: &Config) -> Option<PathBuf> {
+    let cache_dir = dirs::cache_dir().map(|dir| dir.join("csm"));
+    if let Some(cache_dir) = &cache_dir
+        && (config.noop_mode || is_writable(cache_dir))
+    {
+        return Some(cache_dir.clone());
+    }
+    let fallback = temp_cache_dir()?;
+    match &cache_dir {
+        Some(cache_dir) => info!(
+            "The cache directory {} is not writable, using {} instead. \
+             Set cache_dir in .csmrc to choose another one",
+            cache_dir.display(),
+            fallback.display()
+        ),
+        None => info!(
+            "Could not determine the user's cache directory, using {} instead. \
+             Set cache_dir in .csmrc to choose another one",
+            fallback.display()
+        ),
+    }
+    Some(fallback)
+}
+
+/// The cache directory used instead of the usual one with `ephemeral_cache`.
+static EPHEMERAL_CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Pick the cache directory for `ephemeral_cache`, the first time it's
+/// needed. It is named after our process and the time, so that it is neither
+/// the one from an earlier run nor another csm's.
+fn ephemeral_cache_dir() -> &'static PathBuf {
+    EPHEMERAL_CACHE_DIR.get_or_init(|| {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let dir =
+            std::env::temp_dir().join(format!("csm-ephemeral-{}-{}", std::process::id(), nanos));
+        info!("Using the ephemeral cache directory {}", dir.display());
+        dir
+    })
+}
+
+/// Remove the cache directory used with `ephemeral_cache`, if this run used
+/// one and created it.
+pub fn remove_ephemeral_cache() {
+    let Some(dir) = EPHEMERAL_CACHE_DIR.get() else {
+        return;
+    };
+    match std::fs::remove_dir_all(dir) {
+        Ok(()) => debug!("Removed the ephemeral cache directory {}", dir.display()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("Could not remove {}: {}", dir.display(), e),
+    }
+}
+
+/// A cache directory for us in the temporary directory. Others can create
+/// files there too, so on Unix it is specific to our user, and it is not used
+/// if someone else has taken it, since we run the micromamba in it.
+#[cfg(unix)]
+fn temp_cache_dir() -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: getuid() cannot fail and has no preconditions.
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("csm-{}", uid));
+    match std::fs::symlink_metadata(&dir) {
+        Ok(metadata) if metadata.uid() != uid || metadata.mode() & 0o022 != 0 => {
+            warn!(
+                "Not using {} as the cache directory, it belongs to someone else or others can write to it",
+                dir.display()
+            );
+            None
+        }
+        _ => Some(dir),
+    }
+}
+
+/// A cache directory for us in the temporary directory, which on Windows is
+/// already specific to the user.
+#[cfg(not(unix))]
+fn temp_cache_dir() -> Option<PathBuf> {
+    Some(std::env::temp_dir().join("csm"))
+}
+
+/// The configured `mamba_root_prefix`, if any, with `~` and environment
+/// variables expanded.
+pub fn root_prefix_path(config: &Config) -> Option<PathBuf> {
+    config.mamba_root_prefix.as_deref().map(expand_path)
+}
+
+/// How much free space we want in the cache directory, which is enough for
+/// a copy of `micromamba` with room to spare.
+const MIN_CACHE_FREE_SPACE: u64 = 50 * 1024 * 1024;
+
+/// Like [`cache_dir_path()`], but make sure the directory exists, and return
+/// it as an absolute path. Warns if the directory's filesystem is nearly full.
+pub fn csm_cache_dir(config: &Config) -> std::io::Result<PathBuf> {
+    let Some(cache_dir) = cache_dir_path(config) else {
+        return Err(std::io::Error::new(
+            ErrorKind::NotFound,
+            "could not determine the user's cache directory",
+        ));
+    };
+    std::fs::create_dir_all(&cache_dir)?;
+    match free_space(&cache_dir) {
+        Some(free) if free < MIN_CACHE_FREE_SPACE => warn!(
+            "Only {} MB free in the cache directory {}, which may not be enough. \
+             Consider setting cache_dir in .csmrc to a filesystem with more space",
+            free / (1024 * 1024),
+            cache_dir.display()
+        ),
+        Some(_) => {}
+        None => debug!(
+            "Could not determine the free space in {}",
+            cache_dir.display()
+        ),
+    }
+    std::path::absolute(cache_dir)
+}
+
+/// Where csm's own copy of `micromamba` lives, whether or not it exists.
+pub fn cached_micromamba_path(config: &Config) -> Option<PathBuf> {
+    let binary = if cfg!(windows) {
+        "micromamba.exe"
     } else {
-        debug!("About to run: {:?}", cmd);
+        "micromamba"
+    };
+    cache_dir_path(config).map(|dir| dir.join(binary))
+}
+
+/// The `micromamba` binaries we try to run, in order of preference: the one
+/// in $PATH, then our cached copy if there is one.
+fn candidates(config: &Config) -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::from("micromamba")];
+    if let Some(cached) = cached_micromamba_path(config)
+        && cached.is_file()
+    {
+        candidates.push(cached);
+    }
+    candidates
+}
+
+/// Return the first `micromamba` we would try which can actually be run, along
+/// with its version.
+pub fn resolve_micromamba(config: &Config) -> Option<(PathBuf, String)> {
+    candidates(config)
+        .into_iter()
+        .find_map(|path| micromamba_version(&path).map(|version| (path, version)))
+}
+
+/// How long we give `micromamba --version` before giving up on it.
+const VERSION_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Return the output of `<path> --version`, if it can be run.
+fn micromamba_version<P: AsRef<OsStr>>(path: P) -> Option<String> {
+    let child = Command::new(path)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let output = collect_output(child, Some(VERSION_PROBE_TIMEOUT)).ok()??;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The oldest micromamba csm is known to work with. Older ones lack some of the
+/// options csm passes, such as `--platform`.
+const MIN_MICROMAMBA_VERSION: (u64, u64, u64) = (1, 0, 0);
+
+/// Makes sure that we check the version only once, however often we run
+/// micromamba.
+static MIN_VERSION_CHECK: Once = Once::new();
+
+/// The same for comparing the cached micromamba with the one in $PATH.
+static CACHED_VERSION_CHECK: Once = Once::new();
+
+/// Parse a version like `1.5.8` (or `2.0.0rc1`, ignoring the suffix) into its
+/// major, minor and patch components.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = version.trim().split('.').map(|part| {
+        let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+        digits.parse::<u64>().ok()
+    });
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let patch = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Warn if the micromamba at `path` is older than [`MIN_MICROMAMBA_VERSION`].
+fn check_min_version(path: &Path) {
+    let Some(version) = micromamba_version(path) else {
+        debug!("Could not determine the version of {}", path.display());
+        return;
+    };
+    match parse_version(&version) {
+        Some(parsed) if parsed < MIN_MICROMAMBA_VERSION => {
+            let (major, minor, patch) = MIN_MICROMAMBA_VERSION;
+            warn!(
+                "micromamba {} at {} is older than {}.{}.{}, which csm needs. Some commands may fail",
+                version,
+                path.display(),
+                major,
+                minor,
+                patch
+            );
+        }
+        Some(_) => debug!("micromamba at {} has version {}", path.display(), version),
+        None => debug!("Could not parse micromamba version {:?}", version),
+    }
+}
+
+/// When we use the `micromamba` in $PATH, our cached copy silently goes
+/// stale. Warn if both exist and report different versions, so operators can
+/// reconcile them. This is diagnostic only and doesn't affect what we run.
+fn check_cached_version(config: &Config) {
+    let Some(cached) = cached_micromamba_path(config).filter(|path| path.is_file()) else {
+        return;
+    };
+    let Some(path_version) = micromamba_version("micromamba") else {
+        // No micromamba in $PATH, so the cached one is what we'll use.
+        return;
+    };
+    let cached_version = micromamba_version(&cached);
+    if Some(&path_version) == cached_version.as_ref() {
+        debug!(
+            "Cached micromamba at {} has the same version as the one in $PATH",
+            cached.display()
+        );
+    } else {
+        warn!(
+            "Using micromamba from $PATH (version {}), but the cached micromamba at {} has version {}",
+            path_version,
+            cached.display(),
+            cached_version.as_deref().unwrap_or("unknown")
+        );
+    }
+}
+
+/// Render `cmd`, including any environment variables and working directory we
+/// set on it, as a single line which can be pasted into a POSIX shell.
+pub fn shell_command_line(cmd: &Command) -> String {
+    let envs = cmd.get_envs().filter_map(|(key, value)| {
+        value.map(|v| {
+            format!(
+                "{}={}",
+                key.to_string_lossy(),
+                shell_quote(&v.to_string_lossy())
+            )
+        })
+    });
+    let argv = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()));
+    let line = envs.chain(argv).collect::<Vec<_>>().join(" ");
+    match cmd.get_current_dir() {
+        Some(dir) => format!("cd {} && {}", shell_quote(&dir.to_string_lossy()), line),
+        None => line,
+    }
+}
+
+/// Quote `arg` for a POSIX shell, leaving it alone if it is obviously safe.
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
     }
-    cmd
 }
 
 /// Run `micromamba` and return the result, if able.
@@ -62,7 +397,9 @@ pub fn micromamba_at(path: &str, config: &Config, args: Vec<&str>) -> Command {
 /// to download and install `micromamba` into the user's cache directory.
 ///
 /// 1. If there is already a `micromamba` command in $PATH, we use it.
-/// 2. Otherwise, download micromamba and install it somewhere in the user
+/// 2. Otherwise, if there is a `micromamba` in csm's cache directory (see
+///    [`cache_dir_path()`]), we use that.
+/// 3. Otherwise, download micromamba and install it somewhere in the user
 ///    cache directory. (We cannot rely on this - it could be that the user's
 ///    cache directory is mounted noexec or similar, but we try.)
 ///
@@ -74,24 +411,304 @@ pub fn micromamba_at(path: &str, config: &Config, args: Vec<&str>) -> Command {
 ///   based on compile target) and write it to the user cache directory rather
 ///   than downloading it. But this inflates our binary size.
 pub fn micromamba(config: &Config, args: Vec<&str>) -> MicromambaResult {
-    let mut cmd = micromamba_at("micromamba", config, args);
+    micromamba_in(config, args, None)
+}
+
+/// Like [`micromamba()`], but run it in the directory `cwd` if given, rather
+/// than in ours.
+pub fn micromamba_in(config: &Config, args: Vec<&str>, cwd: Option<&Path>) -> MicromambaResult {
+    ensure_root_prefix(config);
+    if config.noop_mode {
+        // Do nothing but log what we would run.
+        info!("Would run: {:?}", micromamba_at("micromamba", config, args));
+        if let Some(cwd) = cwd {
+            info!("Would run it in {}", cwd.display());
+        }
+        return MicromambaResult::Noop;
+    }
+
+    CACHED_VERSION_CHECK.call_once(|| check_cached_version(config));
+
+    // What `run` starts is the user's, which may want a terminal, and may
+    // leave processes behind which keep its stderr open long after it exited.
+    let capture_stderr = crate::status::micromamba_phase(&args) != "running";
+    let mut child = match spawn_micromamba(config, &args, |cmd| {
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+        if capture_stderr {
+            cmd.stderr(Stdio::piped());
+        }
+    }) {
+        Ok(child) => child,
+        Err(result) => return result,
+    };
+    let stderr = relay_in_background(child.stderr.take());
+    match wait_timeout(&mut child, timeout(config)) {
+        Ok(Some(exit_status)) => {
+            // Anything still holding the pipe open after micromamba exited
+            // isn't worth waiting for.
+            let stderr_tail = stderr.recv_timeout(STDERR_GRACE).unwrap_or_default();
+            if !exit_status.success() && !stderr_tail.is_empty() {
+                // It was shown as it happened, but that may have scrolled past
+                // or not been recorded anywhere, unlike our log.
+                error!(
+                    "micromamba failed ({}), the end of its error output was:\n{}",
+                    exit_status,
+                    String::from_utf8_lossy(&stderr_tail).trim_end()
+                );
+            }
+            MicromambaResult::Ok(exit_status)
+        }
+        Ok(None) => {
+            error!("micromamba did not finish in time and was killed");
+            MicromambaResult::TimedOut
+        }
+        Err(e) => {
+            error!("We found a micromamba binary, but failed to wait for it to run");
+            error!("Error was: {}", e);
+            MicromambaResult::CouldNotRun(format!("failed to wait for it: {}", e))
+        }
+    }
+}
 
+/// Like [`micromamba_in()`], but return as soon as micromamba has started,
+/// without waiting for it. It keeps running after csm exits, and the
+/// configured timeout does not apply to it.
+pub fn micromamba_detached(
+    config: &Config,
+    args: Vec<&str>,
+    cwd: Option<&Path>,
+) -> MicromambaResult {
+    ensure_root_prefix(config);
     if config.noop_mode {
-        // Do nothing. micromamba_at() already logged what we're about to run.
+        info!("Would run: {:?}", micromamba_at("micromamba", config, args));
+        if let Some(cwd) = cwd {
+            info!("Would run it in {}", cwd.display());
+        }
         return MicromambaResult::Noop;
     }
 
-    // First we try from $PATH
-    if let Ok(mut child) = cmd.spawn() {
-        debug!("Used micromamba from $PATH");
-        match child.wait() {
-            Ok(exit_status) => return MicromambaResult::Ok(exit_status),
+    CACHED_VERSION_CHECK.call_once(|| check_cached_version(config));
+
+    // Nobody will be there to read from a pipe, so anything but our own
+    // stdout and stderr would get lost.
+    match spawn_micromamba(config, &args, |cmd| {
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.stdin(Stdio::null());
+    }) {
+        Ok(child) => MicromambaResult::Spawned(child.id()),
+        Err(result) => result,
+    }
+}
+
+/// Run `micromamba` with its output captured, for queries whose output we want
+/// to inspect ourselves (e.g. `env list --json`).
+///
+/// The binary is located the same way as in [`micromamba()`]. Since queries
+/// don't change anything, they are run in no-op mode too, so that it can show
+/// what would really happen. They must not be used for anything else.
+pub fn micromamba_output(config: &Config, args: Vec<&str>) -> Result<Output, MicromambaResult> {
+    ensure_root_prefix(config);
+
+    let child = spawn_micromamba(config, &args, |cmd| {
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    })?;
+
+    match collect_output(child, timeout(config)) {
+        Ok(Some(output)) => Ok(output),
+        Ok(None) => {
+            error!("micromamba did not finish in time and was killed");
+            Err(MicromambaResult::TimedOut)
+        }
+        Err(e) => {
+            error!("Failed to wait for micromamba to run: {}", e);
+            Err(MicromambaResult::CouldNotRun(format!(
+                "failed to wait for it: {}",
+                e
+            )))
+        }
+    }
+}
+
+/// Return `$PATH` with `dir` in front of it.
+fn prepend_to_path(dir: &Path) -> Option<OsString> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::join_paths(std::iter::once(dir.to_path_buf()).chain(std::env::split_paths(&path)))
+        .ok()
+}
+
+/// Create the configured root prefix if it doesn't exist yet, since micromamba
+/// fails confusingly otherwise. If that fails, we let micromamba report it.
+fn ensure_root_prefix(config: &Config) {
+    let Some(root_prefix) = root_prefix_path(config) else {
+        return;
+    };
+    if root_prefix.exists() {
+        return;
+    }
+    if config.noop_mode {
+        info!("Would create {}", root_prefix.display());
+    } else if let Err(e) = std::fs::create_dir_all(&root_prefix) {
+        warn!("Could not create {}: {}", root_prefix.display(), e);
+    } else {
+        debug!("Created root prefix {}", root_prefix.display());
+    }
+}
+
+/// The root prefix micromamba will use, if we know it: the configured one, or
+/// else $MAMBA_ROOT_PREFIX.
+pub fn effective_root_prefix(config: &Config) -> Option<PathBuf> {
+    root_prefix_path(config).or_else(|| std::env::var_os("MAMBA_ROOT_PREFIX").map(PathBuf::from))
+}
+
+/// Check that environments can be changed in the root prefix, which
+/// micromamba would otherwise only find out halfway through, e.g. on a
+/// read-only mount. On failure, return the root prefix.
+///
+/// If we don't know the root prefix, or in no-op mode, there is nothing to
+/// check.
+pub fn check_root_prefix_writable(config: &Config) -> Result<(), PathBuf> {
+    match effective_root_prefix(config) {
+        Some(root_prefix) if !config.noop_mode && !is_writable(&root_prefix) => Err(root_prefix),
+        _ => Ok(()),
+    }
+}
+
+/// How long to wait for another csm to finish changing environments in the
+/// same root prefix.
+const ROOT_PREFIX_LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Lock the root prefix against changes by other csm processes, for as long
+/// as the returned file is open. Only other csm processes respect the lock,
+/// micromamba itself doesn't know about it.
+///
+/// Without a configured root prefix we don't know which one micromamba will
+/// use, so there is nothing to lock and we return `None`. In no-op mode we
+/// change nothing, so we don't lock either.
+pub fn lock_root_prefix(config: &Config) -> std::io::Result<Option<File>> {
+    let Some(root_prefix) = effective_root_prefix(config) else {
+        debug!("No root prefix configured, not locking it");
+        return Ok(None);
+    };
+    if config.noop_mode {
+        return Ok(None);
+    }
+    ensure_root_prefix(config);
+    lock_file(&root_prefix.join(".csm.lock"), ROOT_PREFIX_LOCK_TIMEOUT).map(Some)
+}
+
+/// Take an exclusive lock on the file at `path`, creating it if needed, and
+/// waiting up to `timeout` for whoever holds it now.
+fn lock_file(path: &Path, timeout: Duration) -> std::io::Result<File> {
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        if !waiting {
+            info!(
+                "Waiting for the lock {} held by another csm",
+                path.display()
+            );
+            waiting = true;
+        }
+        if start.elapsed() >= timeout {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("timed out waiting for the lock {}", path.display()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Where the micromamba we last ran came from.
+static MICROMAMBA_SOURCE: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Where the micromamba csm last ran came from: `"path"` for the one in
+/// $PATH, `"cache"` for our own copy, or `None` if we haven't run any.
+pub fn micromamba_source() -> Option<&'static str> {
+    *MICROMAMBA_SOURCE.lock().ok()?
+}
+
+/// Spawn the first `micromamba` from [`candidates()`] which can be started,
+/// after calling `prepare` on its [`Command`].
+fn spawn_micromamba<F>(
+    config: &Config,
+    args: &[&str],
+    prepare: F,
+) -> Result<Child, MicromambaResult>
+where
+    F: Fn(&mut Command),
+{
+    crate::status::set_phase(crate::status::micromamba_phase(args));
+    // Whether we found a binary which we could not run, as opposed to finding
+    // nothing at all.
+    let mut found_any = false;
+    // Why each candidate could not be run
+    let mut reasons = vec![];
+
+    for (i, path) in candidates(config).iter().enumerate() {
+        let mut cmd = micromamba_at(path, config, args.to_vec());
+        // Our own copy is not in $PATH, so make it available to anything run
+        // in the environment which calls micromamba itself.
+        if i > 0
+            && let Some(path_var) = path.parent().and_then(prepend_to_path)
+        {
+            cmd.env("PATH", path_var);
+        }
+        prepare(&mut cmd);
+        match cmd.spawn() {
+            Ok(child) => {
+                if i == 0 {
+                    debug!("Used micromamba from $PATH");
+                } else {
+                    debug!("Used cached micromamba at {}", path.display());
+                }
+                MIN_VERSION_CHECK.call_once(|| check_min_version(path));
+                if let Ok(mut source) = MICROMAMBA_SOURCE.lock() {
+                    *source = Some(if i == 0 { "path" } else { "cache" });
+                }
+                return Ok(child);
+            }
+            Err(e) if is_exec_format_error(&e) => {
+                error!(
+                    "{} is not a program for this machine ({} {})",
+                    path.display(),
+                    std::env::consts::OS,
+                    std::env::consts::ARCH
+                );
+                found_any = true;
+                reasons.push(format!(
+                    "{}: not a program for this machine",
+                    path.display()
+                ));
+                // Our own copy is of no use to anyone, so get rid of it rather
+                // than failing the same way every time.
+                if i > 0 {
+                    match std::fs::remove_file(path) {
+                        Ok(()) => warn!("Removed the cached micromamba at {}", path.display()),
+                        Err(e) => warn!("Could not remove {}: {}", path.display(), e),
+                    }
+                }
+            }
             Err(e) => {
-                // In this case don't try to download one, there is probably a
-                // bigger issue.
-                error!("We found a micromamba binary, but failed to wait for it to run");
-                error!("Error was: {}", e);
-                return MicromambaResult::CouldNotRun;
+                debug!("Could not run micromamba at {}: {}", path.display(), e);
+                found_any |= e.kind() != ErrorKind::NotFound;
+                reasons.push(format!("{}: {}", path.display(), e));
             }
         }
     }
@@ -102,9 +719,246 @@ pub fn micromamba(config: &Config, args: Vec<&str>) -> MicromambaResult {
     // TODO
 
     // Finally, if we couldn't run the downloaded one either, just bail out
-    error!("Could not find a suitable micromamba binary to run");
+    let reasons = reasons.join("; ");
+    error!(
+        "Could not find a suitable micromamba binary to run: {}",
+        reasons
+    );
     error!(
         "Please install micromamba manually, ensure it is executable, and place it somewhere in $PATH"
     );
-    MicromambaResult::CouldNotRun
+    if found_any {
+        Err(MicromambaResult::CouldNotRun(reasons))
+    } else {
+        Err(MicromambaResult::NotFound(reasons))
+    }
+}
+
+/// The OS error for trying to run something which is not an executable for
+/// this platform, e.g. one built for a different architecture.
+#[cfg(unix)]
+const EXEC_FORMAT_ERROR: i32 = libc::ENOEXEC;
+/// ERROR_BAD_EXE_FORMAT
+#[cfg(windows)]
+const EXEC_FORMAT_ERROR: i32 = 193;
+
+fn is_exec_format_error(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(EXEC_FORMAT_ERROR)
+}
+
+/// The configured time limit for a single micromamba run.
+fn timeout(config: &Config) -> Option<Duration> {
+    config.micromamba_timeout.map(Duration::from_secs)
+}
+
+/// When the whole csm run has to be finished, if it has to.
+static DEADLINE: OnceLock<Instant> = OnceLock::new();
+
+/// Make sure no micromamba runs past `deadline`, whatever its own timeout.
+pub fn set_deadline(deadline: Instant) {
+    let _ = DEADLINE.set(deadline);
+}
+
+/// Wait for `child` to exit, but for no longer than `timeout` (if given), or
+/// past the deadline of the whole run. If it takes longer, kill it, reap it
+/// and return `Ok(None)`.
+fn wait_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> std::io::Result<Option<ExitStatus>> {
+    let deadline = timeout
+        .map(|timeout| Instant::now() + timeout)
+        .into_iter()
+        .chain(DEADLINE.get().copied())
+        .min();
+    let Some(deadline) = deadline else {
+        return child.wait().map(Some);
+    };
+    loop {
+        if let Some(exit_status) = child.try_wait()? {
+            return Ok(Some(exit_status));
+        }
+        if Instant::now() >= deadline {
+            // This fails if it has exited in the meantime, which is just as good.
+            let _ = child.kill();
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Wait for `child` like [`wait_timeout()`], collecting whatever it writes to
+/// its piped stdout and stderr.
+fn collect_output(mut child: Child, timeout: Option<Duration>) -> std::io::Result<Option<Output>> {
+    // Read both pipes while we wait, so a chatty child can't block on a full
+    // pipe buffer.
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let spinner = Spinner::start("Waiting for micromamba");
+    let status = wait_timeout(&mut child, timeout);
+    drop(spinner);
+    let Some(status) = status? else {
+        return Ok(None);
+    };
+    Ok(Some(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }))
+}
+
+/// How long micromamba has to run with its output captured before
+/// [`Spinner`] shows up.
+const SPINNER_DELAY: Duration = Duration::from_secs(1);
+
+/// Shows on stderr that csm is waiting, while micromamba's own output is
+/// captured and so can't show it. Only on a terminal, and only once the wait
+/// has become noticeable. It is cleared again when dropped.
+struct Spinner {
+    done: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Whether a [`Spinner`] is showing, since with several micromamba running at
+/// once only one of them may draw.
+static SPINNER_SHOWING: AtomicBool = AtomicBool::new(false);
+
+impl Spinner {
+    fn start(message: &'static str) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let show =
+            std::io::stderr().is_terminal() && !SPINNER_SHOWING.swap(true, Ordering::Relaxed);
+        let thread = show.then(|| {
+            let done = done.clone();
+            thread::spawn(move || {
+                let start = Instant::now();
+                let mut drawn = false;
+                for frame in ['|', '/', '-', '\\'].iter().cycle() {
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if start.elapsed() >= SPINNER_DELAY {
+                        eprint!("\r{} {}", frame, message);
+                        drawn = true;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+                if drawn {
+                    eprint!("\r{}\r", " ".repeat(message.len() + 2));
+                }
+            })
+        });
+        Spinner { done, thread }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+            SPINNER_SHOWING.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Read everything from `pipe` in a separate thread.
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// How much of micromamba's error output [`relay_in_background()`] keeps.
+const STDERR_TAIL_BYTES: usize = 16 * 1024;
+
+/// How long to wait for the rest of micromamba's error output once it exited.
+const STDERR_GRACE: Duration = Duration::from_secs(1);
+
+/// Copy everything from `pipe` to our stderr as it arrives, in a separate
+/// thread, which sends the last [`STDERR_TAIL_BYTES`] of it when the pipe is
+/// closed. Nobody has to wait for that: the thread is left behind if it
+/// doesn't finish.
+fn relay_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut tail = vec![];
+        let Some(mut pipe) = pipe else {
+            let _ = sender.send(tail);
+            return;
+        };
+        let mut buf = [0; 8192];
+        loop {
+            let n = match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            let mut our_stderr = std::io::stderr();
+            let _ = our_stderr.write_all(&buf[..n]);
+            let _ = our_stderr.flush();
+            tail.extend_from_slice(&buf[..n]);
+            if tail.len() > STDERR_TAIL_BYTES {
+                tail.drain(..tail.len() - STDERR_TAIL_BYTES);
+            }
+        }
+        let _ = sender.send(tail);
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_command_line() {
+        let mut cmd = Command::new("micromamba");
+        cmd.args(["run", "-n", "my env", "robot", "it's.robot", ""]);
+        cmd.env("MAMBA_ROOT_PREFIX", "/opt/mamba");
+        assert_eq!(
+            shell_command_line(&cmd),
+            "MAMBA_ROOT_PREFIX=/opt/mamba micromamba run -n 'my env' robot 'it'\\''s.robot' ''"
+        );
+        cmd.current_dir("/my robots");
+        assert!(shell_command_line(&cmd).starts_with("cd '/my robots' && MAMBA_ROOT_PREFIX="));
+    }
+
+    #[test]
+    fn test_lock_file_times_out() {
+        let path = std::env::temp_dir().join(format!("csm_test_lock_{}", std::process::id()));
+        let held = lock_file(&path, Duration::ZERO).unwrap();
+        let err = lock_file(&path, Duration::from_millis(200)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        drop(held);
+        assert!(lock_file(&path, Duration::ZERO).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("1.5.8\n"), Some((1, 5, 8)));
+        assert_eq!(parse_version("2.0.0rc1"), Some((2, 0, 0)));
+        assert_eq!(parse_version("2"), Some((2, 0, 0)));
+        assert_eq!(parse_version("micromamba"), None);
+        assert!(parse_version("0.27.0") < Some(MIN_MICROMAMBA_VERSION));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_timeout_kills_child() {
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let start = Instant::now();
+        let result = wait_timeout(&mut child, Some(Duration::from_millis(200))).unwrap();
+        assert!(result.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // Reaped already, so there is nothing left to wait for.
+        assert!(child.try_wait().unwrap().is_some());
+    }
 }
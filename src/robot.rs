@@ -1,6 +1,17 @@
 use crate::csmrc::Config;
+use crate::doctor::{Report, Status};
+use crate::env::{check_env_file, determine_env_name, existing_env_names, robotmk_metadata};
+use crate::exit;
+use crate::json::Json;
+use crate::micromamba::{
+    MicromambaResult, micromamba_at, micromamba_detached, micromamba_in, shell_command_line,
+};
+use crate::util::read_env_file;
 
-use std::process::ExitCode;
+use clap::ValueEnum;
+use log::{debug, error, info, warn};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
 #[derive(Debug, clap::Subcommand)]
 pub enum Subcommand {
@@ -8,17 +19,539 @@ pub enum Subcommand {
     New(CreateArgs),
 
     /// Run a Robotmk robot
-    Run,
+    Run(RunArgs),
+
+    /// List the robots in a directory tree
+    List(ListArgs),
+
+    /// Check that a robot could be run, without running it: that its
+    /// robotmk-env.yaml parses, its environment exists and its suite does
+    Validate(ValidateArgs),
 }
 
 #[derive(Debug, clap::Args)]
 pub struct CreateArgs {
     /// Directory path at which to create the robot
-    path: String,
+    #[arg(required_unless_present = "list_templates")]
+    pub path: Option<String>,
+
+    /// The kind of robot to start from
+    #[arg(long, default_value = "basic")]
+    pub template: Template,
+
+    /// List the templates which --template can choose from, and exit
+    #[arg(long)]
+    pub list_templates: bool,
+
+    /// Overwrite files which exist already, keeping a backup of each with a
+    /// .bak suffix
+    #[arg(long)]
+    pub force: bool,
+
+    /// Allow creating the robot outside of the current directory
+    #[arg(long)]
+    pub allow_outside: bool,
 }
 
-pub fn run(config: Config, subcommand: Subcommand) -> ExitCode {
-    println!("{:?}", config);
-    println!("{:?}", subcommand);
-    ExitCode::SUCCESS
+#[derive(Debug, clap::Args)]
+pub struct ListArgs {
+    /// The directory to look for robots in
+    #[arg(long, default_value = ".")]
+    pub path: String,
+
+    /// Descend at most this many directories below --path. By default, the
+    /// whole tree is searched
+    #[arg(long, value_name = "DEPTH")]
+    pub max_depth: Option<usize>,
+
+    /// Print the robots as a JSON array of objects with `path`, `env_name`
+    /// and `robotmk` fields, the latter with the `robotmk` section of each
+    /// robot's robotmk-env.yaml
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ValidateArgs {
+    /// The name of the robot's environment. If not specified, it is
+    /// determined the same way as for `csm env create`
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// The Robot Framework suite, relative to --path
+    #[arg(default_value = "robot.robot")]
+    pub suite: String,
+
+    /// The robot's directory
+    #[arg(long, default_value = ".")]
+    pub path: String,
+}
+
+/// Make sure `path` stays within `cwd`, unless `allow_outside` is set: it must
+/// not contain `..`, and once symlinks are resolved, it must be below `cwd`.
+fn check_robot_path(path: &Path, cwd: &Path, allow_outside: bool) -> Result<(), String> {
+    if allow_outside {
+        return Ok(());
+    }
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(format!(
+            "{} contains '..'. Use --allow-outside if this is intended",
+            path.display()
+        ));
+    }
+    // A symlink in cwd may well point elsewhere.
+    if !canonicalize_existing(&cwd.join(path)).starts_with(canonicalize_existing(cwd)) {
+        return Err(format!(
+            "{} is outside of the current directory. Use --allow-outside if this is intended",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Resolve the symlinks in the part of `path` which exists, and append the
+/// rest, which doesn't exist yet, as it is.
+fn canonicalize_existing(path: &Path) -> PathBuf {
+    for existing in path.ancestors() {
+        if let Ok(canonical) = existing.canonicalize() {
+            let rest = path.strip_prefix(existing).unwrap_or(Path::new(""));
+            return canonical.join(rest);
+        }
+    }
+    path.to_path_buf()
+}
+
+/// The files `robot new` creates.
+const ROBOT_FILES: &[&str] = &["robotmk-env.yaml", "robot.robot"];
+
+/// The robots `robot new` can start from.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Template {
+    /// A robot which only logs a message
+    Basic,
+    /// A robot which opens a web page, with the Browser library (Playwright)
+    Browser,
+    /// A robot which calls an HTTP API, with RequestsLibrary
+    Api,
+}
+
+impl Template {
+    /// The contents of the [`ROBOT_FILES`], in the same order.
+    fn contents(self) -> [&'static str; 2] {
+        match self {
+            Self::Basic => [
+                include_str!("../templates/robot/basic/robotmk-env.yaml"),
+                include_str!("../templates/robot/basic/robot.robot"),
+            ],
+            Self::Browser => [
+                include_str!("../templates/robot/browser/robotmk-env.yaml"),
+                include_str!("../templates/robot/browser/robot.robot"),
+            ],
+            Self::Api => [
+                include_str!("../templates/robot/api/robotmk-env.yaml"),
+                include_str!("../templates/robot/api/robot.robot"),
+            ],
+        }
+    }
+}
+
+/// Print the templates with their descriptions, one per line.
+fn list_templates() {
+    for template in Template::value_variants() {
+        if let Some(value) = template.to_possible_value() {
+            let help = value
+                .get_help()
+                .map(ToString::to_string)
+                .unwrap_or_default();
+            println!("{}\t{}", value.get_name(), help);
+        }
+    }
+}
+
+/// Create the files of a new robot from `args.template` in `dir`. Files which
+/// exist already are skipped, or with --force backed up and replaced.
+fn new_robot(config: &Config, dir: &Path, args: &CreateArgs) -> std::io::Result<()> {
+    check_robot_path(dir, &std::env::current_dir()?, args.allow_outside)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    if config.noop_mode {
+        if !dir.exists() {
+            info!("Would create {}", dir.display());
+        }
+    } else {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Writing would follow a symlink, wherever it points.
+    if let Some(link) = ROBOT_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_symlink())
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is a symlink, not replacing it", link.display()),
+        ));
+    }
+
+    for (name, contents) in ROBOT_FILES.iter().zip(args.template.contents()) {
+        let path = dir.join(name);
+        if path.exists() {
+            if !args.force {
+                warn!(
+                    "Not creating {}, it already exists. Use --force to replace it",
+                    path.display()
+                );
+                continue;
+            }
+            let backup = dir.join(format!("{}.bak", name));
+            if config.noop_mode {
+                info!("Would back up {} to {}", path.display(), backup.display());
+            } else {
+                debug!("Backing up {} to {}", path.display(), backup.display());
+                fs::rename(&path, &backup)?;
+            }
+        }
+        if config.noop_mode {
+            info!("Would create {}", path.display());
+        } else {
+            debug!("Creating {}", path.display());
+            fs::write(&path, contents)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RunArgs {
+    /// The name of the environment to run the robot in. If not specified, it is
+    /// determined the same way as for `csm env create`
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// The Robot Framework suite to run, relative to --path
+    #[arg(default_value = "robot.robot")]
+    pub suite: String,
+
+    /// The robot's directory, in which to look for robotmk-env.yaml and the
+    /// suite
+    #[arg(long, default_value = ".")]
+    pub path: String,
+
+    /// Run the robot in this directory instead of the current one
+    #[arg(long, value_name = "DIR")]
+    pub cwd: Option<PathBuf>,
+
+    /// Set the environment variables from this file of KEY=VALUE lines for
+    /// the robot
+    #[arg(long, value_name = "PATH")]
+    pub env_file: Option<PathBuf>,
+
+    /// Start the robot, print the process ID of micromamba and exit without
+    /// waiting for it to finish. The robot keeps running after csm exits
+    #[arg(long, conflicts_with = "dry_run")]
+    pub detach: bool,
+
+    /// Resolve the environment name and suite, print the command which would
+    /// be run, but do not run it. Unlike --noop, this still reads files
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Whether `dir` looks like a robot, i.e. has any of the files `robot new`
+/// creates.
+fn is_robot(dir: &Path) -> bool {
+    ROBOT_FILES.iter().any(|name| dir.join(name).is_file())
+}
+
+/// Return the robot directories in the tree below `dir` (including `dir`
+/// itself), descending at most `max_depth` levels. Hidden directories, like
+/// `.git`, and symlinks are skipped.
+fn find_robots(dir: &Path, max_depth: Option<usize>) -> Vec<PathBuf> {
+    let mut robots = vec![];
+    if is_robot(dir) {
+        robots.push(dir.to_path_buf());
+    }
+    if max_depth == Some(0) {
+        return robots;
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Could not read {}: {}", dir.display(), e);
+            return robots;
+        }
+    };
+    let mut subdirs: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .map(|entry| entry.path())
+        .collect();
+    subdirs.sort();
+    for subdir in subdirs {
+        robots.extend(find_robots(&subdir, max_depth.map(|depth| depth - 1)));
+    }
+    robots
+}
+
+pub fn run(config: Config, subcommand: Subcommand) -> u8 {
+    match subcommand {
+        Subcommand::New(args) => {
+            let Some(path) = args.path.as_deref().filter(|_| !args.list_templates) else {
+                list_templates();
+                return exit::SUCCESS;
+            };
+            match new_robot(&config, Path::new(path), &args) {
+                Ok(()) => exit::SUCCESS,
+                Err(e) => {
+                    error!("Could not create robot in {}: {}", path, e);
+                    exit::FAILURE
+                }
+            }
+        }
+        Subcommand::Run(args) => {
+            let mut config = config;
+            if let Some(env_file) = &args.env_file {
+                match read_env_file(env_file) {
+                    Ok(vars) => config.extra_env = vars,
+                    Err(e) => {
+                        error!("Could not read environment file {}", e);
+                        return exit::FAILURE;
+                    }
+                }
+            }
+            let dir = Path::new(&args.path);
+            let env_name = match determine_env_name(&config, args.name, dir) {
+                Ok(env_name) => env_name,
+                Err(e) => {
+                    error!("{}", e);
+                    return e.exit_code();
+                }
+            };
+            let suite = dir.join(&args.suite);
+            if !suite.is_file() {
+                error!("Robot suite {} does not exist", suite.display());
+                return exit::FAILURE;
+            }
+            if let Some(cwd) = &args.cwd
+                && !cwd.is_dir()
+            {
+                error!("{} is not a directory", cwd.display());
+                return exit::FAILURE;
+            }
+            // The suite is relative to our directory, not to --cwd.
+            let suite = match args.cwd {
+                Some(_) => std::path::absolute(&suite).unwrap_or(suite),
+                None => suite,
+            };
+            let suite = suite.to_string_lossy();
+            let run_args = vec!["run", "--name", &env_name, "robot", &suite];
+            if args.dry_run {
+                let mut cmd = micromamba_at("micromamba", &config, run_args);
+                if let Some(cwd) = &args.cwd {
+                    cmd.current_dir(cwd);
+                }
+                println!("{}", shell_command_line(&cmd));
+                return exit::SUCCESS;
+            }
+            if args.detach {
+                let result = micromamba_detached(&config, run_args, args.cwd.as_deref());
+                if let MicromambaResult::Spawned(pid) = result {
+                    println!("{}", pid);
+                }
+                return result.exit_code();
+            }
+            micromamba_in(&config, run_args, args.cwd.as_deref()).exit_code()
+        }
+        Subcommand::List(args) => {
+            let robots = find_robots(Path::new(&args.path), args.max_depth);
+            let robots = robots.iter().map(|dir| {
+                let env_name = determine_env_name(&config, None, dir)
+                    .inspect_err(|e| warn!("{}: {}", dir.display(), e))
+                    .ok();
+                (dir, env_name)
+            });
+            if args.json {
+                let objects = robots
+                    .map(|(dir, env_name)| {
+                        let metadata = robotmk_metadata(&config, dir)
+                            .inspect_err(|e| warn!("{}: {}", dir.display(), e))
+                            .ok()
+                            .flatten();
+                        Json::object([
+                            ("path", Json::from(dir.display().to_string())),
+                            ("env_name", Json::from(env_name)),
+                            (
+                                "robotmk",
+                                metadata.map_or(Json::Null, |metadata| metadata.to_json()),
+                            ),
+                        ])
+                    })
+                    .collect();
+                println!("{}", Json::Array(objects));
+            } else {
+                for (dir, env_name) in robots {
+                    println!("{}\t{}", dir.display(), env_name.as_deref().unwrap_or("-"));
+                }
+            }
+            exit::SUCCESS
+        }
+        Subcommand::Validate(args) => validate_robot(&config, args),
+    }
+}
+
+/// Report on each of the things `robot run` needs, and fail if any is missing.
+fn validate_robot(config: &Config, args: ValidateArgs) -> u8 {
+    let mut report = Report::new();
+    let dir = Path::new(&args.path);
+
+    match check_env_file(config, dir) {
+        Ok(Some(path)) => report.line(Status::Pass, "Environment file", path.display().to_string()),
+        Ok(None) => report.line(
+            Status::Warn,
+            "Environment file",
+            format!("none in {}", dir.display()),
+        ),
+        Err(e) => report.line(Status::Fail, "Environment file", e.to_string()),
+    }
+
+    match determine_env_name(config, args.name, dir) {
+        Err(e) => report.line(Status::Fail, "Environment", e.to_string()),
+        Ok(env_name) => match existing_env_names(config) {
+            Ok(names) if names.contains(&env_name) => report.line(
+                Status::Pass,
+                "Environment",
+                format!("'{}' exists", env_name),
+            ),
+            Ok(_) => report.line(
+                Status::Fail,
+                "Environment",
+                format!("'{}' does not exist", env_name),
+            ),
+            Err(e) => report.line(Status::Fail, "Environment", e.to_string()),
+        },
+    }
+
+    let suite = dir.join(&args.suite);
+    if suite.is_file() {
+        report.line(Status::Pass, "Suite", suite.display().to_string());
+    } else {
+        report.line(
+            Status::Fail,
+            "Suite",
+            format!("{} does not exist", suite.display()),
+        );
+    }
+
+    report.exit_code()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_new_robot_keeps_or_backs_up_existing_files() {
+        let dir = env::temp_dir().join("csm_test_new_robot");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("robot.robot"), "mine").unwrap();
+        let mut args = CreateArgs {
+            path: None,
+            template: Template::Basic,
+            list_templates: false,
+            force: false,
+            allow_outside: true,
+        };
+
+        new_robot(&Config::default(), &dir, &args).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("robot.robot")).unwrap(), "mine");
+        assert!(dir.join("robotmk-env.yaml").is_file());
+
+        args.force = true;
+        new_robot(&Config::default(), &dir, &args).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("robot.robot.bak")).unwrap(),
+            "mine"
+        );
+        assert_ne!(fs::read_to_string(dir.join("robot.robot")).unwrap(), "mine");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_templates() {
+        let dir = env::temp_dir().join("csm_test_robot_templates");
+        for template in Template::value_variants() {
+            let args = CreateArgs {
+                path: None,
+                template: *template,
+                list_templates: false,
+                force: true,
+                allow_outside: true,
+            };
+            new_robot(&Config::default(), &dir, &args).unwrap();
+            let env_file = check_env_file(&Config::default(), &dir);
+            assert!(matches!(env_file, Ok(Some(_))), "{:?}", template);
+            assert!(is_robot(&dir));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_robot_path() {
+        let cwd = env::temp_dir().join("project");
+        let in_cwd = cwd.join("robots").join("new");
+        let outside = env::temp_dir().join("elsewhere");
+        let traversal = Path::new("..").join("..").join("etc").join("evil");
+        let sneaky = Path::new("robots").join("..").join("..").join("evil");
+
+        assert!(check_robot_path(Path::new("robots/new"), &cwd, false).is_ok());
+        assert!(check_robot_path(&in_cwd, &cwd, false).is_ok());
+        assert!(check_robot_path(&outside, &cwd, false).is_err());
+        assert!(check_robot_path(&traversal, &cwd, false).is_err());
+        assert!(check_robot_path(&sneaky, &cwd, false).is_err());
+        assert!(check_robot_path(&traversal, &cwd, true).is_ok());
+        assert!(check_robot_path(&outside, &cwd, true).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_robot_path_resolves_symlinks() {
+        let cwd = env::temp_dir().join("csm_test_robot_path_symlinks");
+        let outside = env::temp_dir().join("csm_test_robot_path_outside");
+        fs::create_dir_all(&cwd).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        let _ = fs::remove_file(cwd.join("escape"));
+        std::os::unix::fs::symlink(&outside, cwd.join("escape")).unwrap();
+        fs::create_dir_all(cwd.join("inside")).unwrap();
+
+        assert!(check_robot_path(Path::new("escape/new"), &cwd, false).is_err());
+        assert!(check_robot_path(&cwd.join("escape"), &cwd, false).is_err());
+        assert!(check_robot_path(Path::new("inside/new"), &cwd, false).is_ok());
+        assert!(check_robot_path(Path::new("escape/new"), &cwd, true).is_ok());
+
+        fs::remove_dir_all(&cwd).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_find_robots() {
+        let dir = env::temp_dir().join("csm_test_find_robots");
+        for robot in ["a", "b/c", ".hidden", "d/e/f"] {
+            fs::create_dir_all(dir.join(robot)).unwrap();
+            fs::write(dir.join(robot).join("robot.robot"), "").unwrap();
+        }
+
+        assert_eq!(
+            find_robots(&dir, None),
+            vec![dir.join("a"), dir.join("b/c"), dir.join("d/e/f")]
+        );
+        assert_eq!(
+            find_robots(&dir, Some(2)),
+            vec![dir.join("a"), dir.join("b/c")]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
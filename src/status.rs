@@ -0,0 +1,85 @@
+//! The --status-file, a heartbeat which a supervisor can watch to notice a
+//! run of csm which got stuck.
+//!
+//! The file holds a JSON object with csm's `pid`, the `phase` it is in and a
+//! `timestamp` (in seconds since the Unix epoch). It is rewritten whenever the
+//! phase changes, and otherwise every [`HEARTBEAT_INTERVAL`], so a timestamp
+//! much older than that means csm is not making progress. It is about csm
+//! itself: after `robot run --detach`, the phase is `done` as soon as csm
+//! exits, though the robot keeps running.
+
+use crate::json::Json;
+use crate::util::write_atomically;
+use log::warn;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the status file is rewritten when nothing changes.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The status file and the phase to write into it, once [`start()`] was
+/// called.
+static STATUS: Mutex<Option<(PathBuf, &'static str)>> = Mutex::new(None);
+
+/// Makes sure we only warn once if the status file can't be written.
+static WRITE_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Start keeping `path` up to date, in the phase `starting`.
+pub fn start(path: PathBuf) {
+    if let Ok(mut status) = STATUS.lock() {
+        *status = Some((path, "starting"));
+        write(&status);
+    }
+    std::thread::spawn(|| {
+        loop {
+            std::thread::sleep(HEARTBEAT_INTERVAL);
+            if let Ok(status) = STATUS.lock() {
+                write(&status);
+            }
+        }
+    });
+}
+
+/// Record that csm is now in `phase`. Without a status file, this does
+/// nothing.
+pub fn set_phase(phase: &'static str) {
+    if let Ok(mut status) = STATUS.lock()
+        && let Some((_, current)) = status.as_mut()
+        && *current != phase
+    {
+        *current = phase;
+        write(&status);
+    }
+}
+
+/// The phase in which csm runs micromamba with `args`.
+pub fn micromamba_phase(args: &[&str]) -> &'static str {
+    let subcommand = args.iter().find(|arg| **arg != "env");
+    match subcommand.copied() {
+        Some("run") => "running",
+        Some("create" | "install" | "update") => "solving",
+        Some("remove") => "removing",
+        _ => "querying",
+    }
+}
+
+fn write(status: &Option<(PathBuf, &'static str)>) {
+    let Some((path, phase)) = status else {
+        return;
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let object = Json::object([
+        ("pid", Json::Number(std::process::id().into())),
+        ("phase", Json::from(*phase)),
+        ("timestamp", Json::Number(timestamp as i64)),
+    ]);
+    if let Err(e) = write_atomically(path, &format!("{}\n", object))
+        && !WRITE_FAILED.swap(true, Ordering::Relaxed)
+    {
+        warn!("Could not write {}: {}", path.display(), e);
+    }
+}
@@ -0,0 +1,507 @@
+//! Small helpers shared between the other modules.
+
+use log::warn;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Determine the user's home directory.
+///
+/// Everything in csm that needs the home directory (reading ~/.csmrc, creating
+/// ~/.mambarc, ...) must go through this function so that they all agree. We
+/// look at $HOME first, then %UserProfile%, and only then ask the OS via
+/// [`dirs::home_dir`]. Checking the environment first means a user (or a test)
+/// can redirect csm on every platform the same way.
+pub fn homedir() -> Option<PathBuf> {
+    ["HOME", "USERPROFILE"]
+        .iter()
+        .filter_map(std::env::var_os)
+        .find(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .or_else(dirs::home_dir)
+}
+
+/// Expand a path from the configuration like a shell would: a leading `~` is
+/// replaced by the home directory, and `$VAR`, `${VAR}` and `%VAR%` are
+/// replaced by the value of the environment variable `VAR`.
+///
+/// If a referenced variable is not set, we warn and use the path as it is.
+pub fn expand_path(path: &str) -> PathBuf {
+    let home = || homedir().map(|home| home.to_string_lossy().into_owned());
+    let tilde_expanded = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => match home() {
+            Some(home) => format!("{}{}", home, rest),
+            None => path.to_string(),
+        },
+        _ => path.to_string(),
+    };
+    match expand_vars(&tilde_expanded) {
+        Ok(expanded) => PathBuf::from(expanded),
+        Err(name) => {
+            warn!(
+                "Environment variable {} in path {} is not set, not expanding it",
+                name, path
+            );
+            PathBuf::from(tilde_expanded)
+        }
+    }
+}
+
+/// Replace `$VAR`, `${VAR}` and `%VAR%` in `input` with the value of the
+/// environment variable `VAR`. If any variable is not set, return its name as
+/// the error.
+pub fn expand_vars(input: &str) -> Result<String, String> {
+    expand_vars_with(input, |name| std::env::var(name).ok())
+}
+
+/// Like [`expand_vars()`], but `HOSTNAME` is always known: if it is not set, as
+/// it usually isn't outside of interactive shells, it is this host's name.
+pub fn expand_vars_and_hostname(input: &str) -> Result<String, String> {
+    expand_vars_with(input, |name| {
+        std::env::var(name)
+            .ok()
+            .or_else(|| (name == "HOSTNAME").then(hostname).flatten())
+    })
+}
+
+/// The name of this host, if it can be determined.
+#[cfg(unix)]
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for writes of its length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0)?;
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// The name of this host, if it can be determined.
+#[cfg(not(unix))]
+pub fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Replace `$VAR`, `${VAR}` and `%VAR%` in `input` with what `lookup` returns
+/// for `VAR`. If it returns `None` for any variable, return that variable's
+/// name as the error. Anything that doesn't look like a variable reference,
+/// e.g. a lone `$` or `%`, is kept as it is.
+fn expand_vars_with<F>(input: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut result = String::new();
+    let mut rest = input;
+    while let Some(pos) = rest.find(['$', '%']) {
+        result.push_str(&rest[..pos]);
+        let marker = &rest[pos..pos + 1];
+        let after = &rest[pos + 1..];
+        let (name, remainder) = if marker == "%" {
+            match after.find('%') {
+                Some(end) if end > 0 && after[..end].chars().all(is_name_char) => {
+                    (&after[..end], &after[end + 1..])
+                }
+                _ => ("", after),
+            }
+        } else if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) if end > 0 && braced[..end].chars().all(is_name_char) => {
+                    (&braced[..end], &braced[end + 1..])
+                }
+                _ => ("", after),
+            }
+        } else {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        if name.is_empty() {
+            result.push_str(marker);
+        } else {
+            result.push_str(&lookup(name).ok_or_else(|| name.to_string())?);
+        }
+        rest = remainder;
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Read a dotenv file of `KEY=VALUE` lines, returning the variables in the
+/// order they appear. See [`parse_env_file()`] for the format.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse_env_file(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Parse the contents of a dotenv file: one `KEY=VALUE` per line, optionally
+/// preceded by `export`. Blank lines and lines starting with `#` are ignored.
+/// Values may be in single quotes, which are taken literally, or in double
+/// quotes, in which `\"`, `\\` and `\n` are escapes. Unquoted values end at a
+/// ` #` comment and are trimmed.
+fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected KEY=VALUE", i + 1));
+        };
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("line {}: invalid variable name {:?}", i + 1, key));
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted
+                .strip_suffix('\'')
+                .ok_or_else(|| format!("line {}: unterminated quote", i + 1))?
+                .to_string()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let quoted = quoted
+                .strip_suffix('"')
+                .ok_or_else(|| format!("line {}: unterminated quote", i + 1))?;
+            let mut unescaped = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match (c, chars.clone().next()) {
+                    ('\\', Some('n')) => unescaped.push('\n'),
+                    ('\\', Some(escaped @ ('"' | '\\'))) => unescaped.push(escaped),
+                    _ => {
+                        unescaped.push(c);
+                        continue;
+                    }
+                }
+                chars.next();
+            }
+            unescaped
+        } else {
+            match value.find(" #") {
+                Some(comment) => value[..comment].trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+/// Counts the temporary files [`write_atomically()`] made, to tell them apart.
+static TMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Write `contents` to `path`, replacing it atomically so that readers never
+/// see it half written. Each write goes through a temporary file of its own,
+/// so that writers in other threads or processes don't clobber it.
+pub fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = PathBuf::from(tmp_name);
+    let result =
+        std::fs::write(&tmp_path, contents).and_then(|()| std::fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Whether `name` matches the shell-style glob `pattern`, in which `*` stands
+/// for any number of characters and `?` for exactly one.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of `name` it has matched so far.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the `*` match one more character, and try again.
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The SHA-256 digest of `data`, in lowercase hex.
+pub fn sha256_hex(data: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    // Pad with a 1 bit, zeros and the length in bits to a multiple of 64 bytes.
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend(((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    h.iter().map(|word| format!("{:08x}", word)).collect()
+}
+
+/// Whether we can create files in `dir`, or if it doesn't exist yet, in the
+/// nearest of its parents which does. The permissions alone don't tell, e.g.
+/// on a read-only filesystem, so we actually try.
+pub fn is_writable(dir: &Path) -> bool {
+    let Some(existing) = dir.ancestors().find(|dir| dir.exists()) else {
+        return false;
+    };
+    let probe = existing.join(format!(".csm-probe-{}", std::process::id()));
+    match std::fs::File::create_new(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Return the number of bytes available to us on the filesystem containing
+/// `path`, or `None` if that cannot be determined.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is NUL-terminated, and stat is only read if statvfs()
+    // succeeded and thus filled it in.
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Return the number of bytes available to us on the filesystem containing
+/// `path`, or `None` if that cannot be determined.
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut available = 0u64;
+    // SAFETY: wide is NUL-terminated, and the other out pointers may be null.
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (ok != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_hostname() {
+        let hostname = hostname().unwrap();
+        assert!(!hostname.is_empty());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("robot-ci-*", "robot-ci-123"));
+        assert!(glob_match("robot-ci-*", "robot-ci-"));
+        assert!(!glob_match("robot-ci-*", "robot-prod"));
+        assert!(glob_match("*-ci-?", "robot-ci-1"));
+        assert!(!glob_match("*-ci-?", "robot-ci-12"));
+        assert!(glob_match("a*b*c", "axxbyybc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Longer than one block
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_expand_vars_with() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/me".to_string()),
+            "APPDATA" => Some("C:\\Users\\me\\AppData".to_string()),
+            _ => None,
+        };
+        let cases = [
+            ("$HOME/envs", "/home/me/envs"),
+            ("${HOME}envs", "/home/meenvs"),
+            ("%APPDATA%\\csm", "C:\\Users\\me\\AppData\\csm"),
+            ("/no/vars", "/no/vars"),
+            ("cost$ 100%", "cost$ 100%"),
+            ("${unclosed", "${unclosed"),
+            ("50%-%", "50%-%"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(expand_vars_with(input, lookup).unwrap(), expected);
+        }
+        assert_eq!(
+            expand_vars_with("$UNSET/x", lookup),
+            Err("UNSET".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let contents = r#"
+# Proxy settings
+HTTPS_PROXY=http://proxy:3128 # the site proxy
+export NO_PROXY = localhost
+GREETING="say \"hi\"\nback\\"
+LITERAL='$HOME \n'
+EMPTY=
+"#;
+        assert_eq!(
+            parse_env_file(contents).unwrap(),
+            [
+                ("HTTPS_PROXY", "http://proxy:3128"),
+                ("NO_PROXY", "localhost"),
+                ("GREETING", "say \"hi\"\nback\\"),
+                ("LITERAL", "$HOME \\n"),
+                ("EMPTY", ""),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+        assert!(
+            parse_env_file("no equals sign")
+                .unwrap_err()
+                .contains("line 1")
+        );
+        assert!(
+            parse_env_file("\nBAD KEY=1")
+                .unwrap_err()
+                .contains("line 2")
+        );
+        assert!(parse_env_file("A=\"open").is_err());
+    }
+
+    #[test]
+    fn test_free_space() {
+        assert!(free_space(&env::temp_dir()).is_some_and(|bytes| bytes > 0));
+        assert_eq!(free_space(Path::new("/does/not/exist")), None);
+    }
+
+    #[test]
+    fn test_write_atomically_concurrently() {
+        let dir = env::temp_dir().join("csm_test_write_atomically");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("result.json");
+
+        std::thread::scope(|scope| {
+            for writer in ["a", "b"] {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..100 {
+                        write_atomically(path, writer).unwrap();
+                    }
+                });
+            }
+        });
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents == "a" || contents == "b");
+        // No temporary files are left behind.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+//! Test harness which runs csm against a fake `micromamba`.
+//!
+//! The fake is a shell script put first in `$PATH`. It records every call in
+//! a file and answers with canned output and exit codes, so tests don't depend
+//! on a real micromamba being installed.
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub struct FakeMicromamba {
+    root: PathBuf,
+    responses: Vec<(String, String, i32)>,
+    default_exit_code: i32,
+    version: String,
+}
+
+impl FakeMicromamba {
+    /// Set up a fresh directory tree for a test called `name`: a home
+    /// directory, a `bin` directory for the fake and a project directory
+    /// (named `project`) to run csm in.
+    pub fn new(name: &str) -> Self {
+        let root = env::temp_dir().join(format!("csm-fake-{}-{}", name, std::process::id()));
+        if root.exists() {
+            fs::remove_dir_all(&root).unwrap();
+        }
+        for dir in ["home", "bin", "project"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        Self {
+            root,
+            responses: vec![],
+            default_exit_code: 0,
+            version: "2.0.0".to_string(),
+        }
+    }
+
+    /// When the arguments start with `args_prefix`, print `stdout` and exit
+    /// with `exit_code`. The first matching response wins.
+    pub fn respond(mut self, args_prefix: &str, stdout: &str, exit_code: i32) -> Self {
+        self.responses
+            .push((args_prefix.to_string(), stdout.to_string(), exit_code));
+        self
+    }
+
+    /// The exit code for calls which match no response.
+    pub fn default_exit_code(mut self, exit_code: i32) -> Self {
+        self.default_exit_code = exit_code;
+        self
+    }
+
+    /// The version the fake reports for `--version`, which is not recorded as
+    /// a call.
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Write the fake `micromamba` script.
+    pub fn install(self) -> Self {
+        let calls = self.root.join("calls");
+        let mut script = format!(
+            "#!/bin/sh\n[ \"$*\" = --version ] && echo '{}' && exit 0\necho \"$*\" >> '{}'\ncase \"$*\" in\n",
+            self.version,
+            calls.display()
+        );
+        for (i, (args_prefix, stdout, exit_code)) in self.responses.iter().enumerate() {
+            let out_file = self.root.join(format!("response-{}", i));
+            fs::write(&out_file, stdout).unwrap();
+            script.push_str(&format!(
+                "  '{}'*) cat '{}'; exit {};;\n",
+                args_prefix,
+                out_file.display(),
+                exit_code
+            ));
+        }
+        // Like a real micromamba without environments, unless told otherwise
+        script.push_str(&format!(
+            "  'env list'*) echo '{{\"envs\": []}}'; exit {};;\n",
+            self.default_exit_code
+        ));
+        script.push_str(&format!("esac\nexit {}\n", self.default_exit_code));
+
+        let path = self.root.join("bin").join("micromamba");
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        self
+    }
+
+    /// The directory csm is run in.
+    pub fn project_dir(&self) -> PathBuf {
+        self.root.join("project")
+    }
+
+    /// Where csm looks for its own copy of micromamba.
+    pub fn cached_micromamba(&self) -> PathBuf {
+        self.root.join("home/.cache/csm/micromamba")
+    }
+
+    /// The home directory csm runs with.
+    pub fn home_dir(&self) -> PathBuf {
+        self.root.join("home")
+    }
+
+    /// A [`Command`] running csm with `args` against the fake, isolated from
+    /// the real home and cache directories.
+    pub fn csm(&self, args: &[&str]) -> Command {
+        let home = self.home_dir();
+        // Only the basics, so that a real micromamba can't be found.
+        let path =
+            env::join_paths([self.root.join("bin"), "/usr/bin".into(), "/bin".into()]).unwrap();
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_csm"));
+        cmd.args(args)
+            .current_dir(self.project_dir())
+            .env("PATH", path)
+            .env("HOME", &home)
+            .env("USERPROFILE", &home)
+            .env("XDG_CACHE_HOME", home.join(".cache"))
+            .env_remove("MAMBA_ROOT_PREFIX")
+            .env_remove("RUST_LOG");
+        cmd
+    }
+
+    /// The argument lists the fake was called with, in order.
+    pub fn calls(&self) -> Vec<String> {
+        read_lines(&self.root.join("calls"))
+    }
+}
+
+impl Drop for FakeMicromamba {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn read_lines(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|s| s.lines().map(String::from).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,1556 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::FakeMicromamba;
+
+const ENV_LIST: &str = r#"{"envs": ["/opt/mamba", "/opt/mamba/envs/existing"]}"#;
+
+#[test]
+fn test_env_create_propagates_exit_code() {
+    let fake = FakeMicromamba::new("exit-code")
+        .respond("env list", ENV_LIST, 0)
+        .respond("create", "", 3)
+        .install();
+
+    let status = fake
+        .csm(&["env", "create", "--name", "new"])
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(3));
+    assert_eq!(
+        fake.calls(),
+        vec!["env list --json", "create --name new --yes"]
+    );
+}
+
+#[test]
+fn test_env_create_refuses_existing_env() {
+    let fake = FakeMicromamba::new("existing")
+        .respond("env list", ENV_LIST, 0)
+        .install();
+
+    let output = fake
+        .csm(&["env", "create", "--name", "existing"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+    assert_eq!(fake.calls(), vec!["env list --json"]);
+}
+
+#[test]
+fn test_env_create_force_skips_existence_check() {
+    let fake = FakeMicromamba::new("force").default_exit_code(0).install();
+
+    let status = fake
+        .csm(&["env", "create", "--name", "existing", "--force"])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(fake.calls(), vec!["create --name existing --yes"]);
+}
+
+#[test]
+fn test_missing_micromamba_exit_code() {
+    // Never installed, so there is no micromamba at all.
+    let fake = FakeMicromamba::new("missing");
+
+    let output = fake
+        .csm(&["env", "create", "--name", "new", "--force"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(127));
+    // Say why, not just that it failed
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("micromamba could not be found (micromamba: No such file or directory")
+    );
+}
+
+#[test]
+fn test_env_run_passes_command_through() {
+    let fake = FakeMicromamba::new("run")
+        .respond("run --name proj python", "", 5)
+        .install();
+
+    let status = fake
+        .csm(&["env", "run", "--name", "proj", "--", "python", "-c", "pass"])
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(5));
+    assert_eq!(fake.calls(), vec!["run --name proj python -c pass"]);
+}
+
+#[test]
+fn test_env_list_json_with_counts() {
+    let fake = FakeMicromamba::new("list-json")
+        .respond("env list", ENV_LIST, 0)
+        .respond("list --prefix /opt/mamba/envs/existing", "[{}, {}, {}]", 0)
+        .respond("list --prefix /opt/mamba", "[{}]", 0)
+        .install();
+
+    let output = fake
+        .csm(&["env", "list", "--json", "--counts"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        r#"[{"name":"base","prefix":"/opt/mamba","package_count":1},{"name":"existing","prefix":"/opt/mamba/envs/existing","package_count":3}]"#
+    );
+}
+
+#[test]
+fn test_foreign_cached_micromamba_is_removed() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeMicromamba::new("foreign");
+    let cached = fake.cached_micromamba();
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    // Neither a script nor a binary for any platform.
+    std::fs::write(&cached, [0x7f, b'X', b'Y', b'Z', 0, 0, 0, 0]).unwrap();
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = fake
+        .csm(&["env", "run", "--name", "proj", "--", "true"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(126));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not a program for this machine"));
+    assert!(!cached.exists());
+}
+
+#[test]
+fn test_root_prefix_is_created() {
+    let fake = FakeMicromamba::new("root-prefix").install();
+    let root_prefix = fake.project_dir().join("new/prefix");
+
+    let status = fake
+        .csm(&[
+            "--root-prefix",
+            root_prefix.to_str().unwrap(),
+            "env",
+            "run",
+            "--name",
+            "proj",
+            "--",
+            "true",
+        ])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert!(root_prefix.is_dir());
+}
+
+#[test]
+fn test_env_create_offline_fallback() {
+    let fake = FakeMicromamba::new("offline-fallback")
+        .respond("create --name new --yes --offline", "", 0)
+        .respond("create", "", 1)
+        .install();
+
+    let status = fake
+        .csm(&[
+            "env",
+            "create",
+            "--name",
+            "new",
+            "--force",
+            "--offline-fallback",
+        ])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        fake.calls(),
+        vec![
+            "create --name new --yes",
+            "create --name new --yes --offline"
+        ]
+    );
+}
+
+#[test]
+fn test_cached_micromamba_is_put_in_path() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeMicromamba::new("cached-path");
+    let cached = fake.cached_micromamba();
+    let seen_path = fake.project_dir().join("seen-path");
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    std::fs::write(
+        &cached,
+        format!(
+            "#!/bin/sh\n[ \"$1\" = --version ] && exit 0\necho \"$PATH\" > '{}'\n",
+            seen_path.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let status = fake
+        .csm(&["env", "run", "--name", "proj", "--", "true"])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    let seen_path = std::fs::read_to_string(seen_path).unwrap();
+    assert!(seen_path.starts_with(&format!("{}:", cached.parent().unwrap().display())));
+}
+
+#[test]
+fn test_env_run_cwd() {
+    let fake = FakeMicromamba::new("run-cwd").install();
+    let cwd = fake.project_dir().join("elsewhere");
+
+    let status = fake
+        .csm(&[
+            "env",
+            "run",
+            "--name",
+            "proj",
+            "--cwd",
+            "elsewhere",
+            "--",
+            "true",
+        ])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(fake.calls().is_empty());
+
+    std::fs::create_dir(&cwd).unwrap();
+    let status = fake
+        .csm(&[
+            "env",
+            "run",
+            "--name",
+            "proj",
+            "--cwd",
+            "elsewhere",
+            "--",
+            "true",
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(fake.calls(), vec!["run --name proj true"]);
+}
+
+#[test]
+fn test_old_micromamba_is_reported() {
+    let fake = FakeMicromamba::new("old-version")
+        .version("0.27.0")
+        .install();
+
+    let output = fake
+        .csm(&["env", "run", "--name", "proj", "--", "true"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("micromamba 0.27.0"));
+}
+
+#[test]
+fn test_env_create_if_missing_skips_existing_env() {
+    let fake = FakeMicromamba::new("if-missing")
+        .respond("env list", ENV_LIST, 0)
+        .install();
+
+    let status = fake
+        .csm(&["env", "create", "--name", "existing", "--if-missing"])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(fake.calls(), vec!["env list --json"]);
+}
+
+#[test]
+fn test_env_update_keep_going() {
+    let fake = FakeMicromamba::new("keep-going")
+        .respond("update --all --name b", "", 2)
+        .install();
+    let names = ["env", "update", "-n", "a", "-n", "b", "-n", "c"];
+
+    let status = fake.csm(&names).status().unwrap();
+    assert_eq!(status.code(), Some(2));
+    assert_eq!(fake.calls().len(), 2);
+
+    let status = fake
+        .csm(&[&names[..], &["--keep-going"]].concat())
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert_eq!(fake.calls().len(), 5);
+}
+
+#[test]
+fn test_env_update_spec_only_for_project_env() {
+    let fake = FakeMicromamba::new("update-spec").install();
+    std::fs::write(
+        fake.project_dir().join("robotmk-env.yaml"),
+        "name: a\ndependencies: [python]\n",
+    )
+    .unwrap();
+
+    let status = fake
+        .csm(&["env", "update", "-n", "a", "-n", "b"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let status = fake.csm(&["env", "update"]).status().unwrap();
+    assert!(status.success());
+
+    assert_eq!(
+        fake.calls(),
+        vec![
+            "install --file ./robotmk-env.yaml --name a --yes",
+            "update --all --name b --yes",
+            "install --file ./robotmk-env.yaml --name a --yes"
+        ]
+    );
+}
+
+#[test]
+fn test_cached_version_is_compared_once() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeMicromamba::new("cached-version").install();
+    let cached = fake.cached_micromamba();
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    std::fs::write(&cached, "#!/bin/sh\necho 1.0.0\n").unwrap();
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = fake
+        .csm(&["env", "update", "-n", "a", "-n", "b", "-n", "c"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(fake.calls().len(), 3);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("has version 1.0.0").count(), 1);
+}
+
+#[test]
+fn test_failed_micromamba_output_is_logged() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeMicromamba::new("stderr-log");
+    let cached = fake.cached_micromamba();
+    let log_file = fake.project_dir().join("csm.log");
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    std::fs::write(
+        &cached,
+        "#!/bin/sh\n[ \"$1\" = --version ] && exit 0\necho 'solver exploded' >&2\nexit 1\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let status = fake
+        .csm(&[
+            "--log-file",
+            "csm.log",
+            "env",
+            "create",
+            "--name",
+            "proj",
+            "--force",
+        ])
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(1));
+    let log = std::fs::read_to_string(log_file).unwrap();
+    assert!(log.contains("end of its error output was:"));
+    assert!(log.contains("solver exploded"));
+}
+
+#[test]
+fn test_background_processes_dont_keep_csm_waiting() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Stdio;
+    use std::time::{Duration, Instant};
+
+    let fake = FakeMicromamba::new("background");
+    let cached = fake.cached_micromamba();
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    // Like a robot starting a daemon, which keeps stderr open.
+    std::fs::write(
+        &cached,
+        "#!/bin/sh\n[ \"$1\" = --version ] && exit 0\nsleep 10 &\nexit 0\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    for args in [
+        &["env", "run", "--name", "proj", "--", "true"][..],
+        &["env", "create", "--name", "proj", "--force"][..],
+    ] {
+        let started = Instant::now();
+        let status = fake
+            .csm(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", args);
+    }
+}
+
+#[test]
+fn test_env_pack_installs_conda_pack() {
+    let fake = FakeMicromamba::new("pack")
+        .respond("run --name proj conda-pack --version", "", 127)
+        .install();
+
+    let status = fake
+        .csm(&["env", "pack", "--name", "proj", "--output", "proj.tar.gz"])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        fake.calls(),
+        vec![
+            "run --name proj conda-pack --version",
+            "install --name proj conda-pack --yes",
+            "run --name proj conda-pack --output proj.tar.gz",
+        ]
+    );
+}
+
+#[test]
+fn test_env_unpack() {
+    let fake = FakeMicromamba::new("unpack").install();
+    let project = fake.project_dir();
+    let root_prefix = project.join("root");
+    std::fs::create_dir_all(project.join("packed/bin")).unwrap();
+    std::fs::write(project.join("packed/bin/tool"), "").unwrap();
+    let tar = std::process::Command::new("tar")
+        .args(["-czf", "robot.tar.gz", "-C", "packed", "."])
+        .current_dir(&project)
+        .status()
+        .unwrap();
+    assert!(tar.success());
+    let root_prefix_arg = root_prefix.to_str().unwrap();
+    let unpack = [
+        "--root-prefix",
+        root_prefix_arg,
+        "env",
+        "unpack",
+        "--input",
+        "robot.tar.gz",
+    ];
+
+    let status = fake.csm(&unpack).status().unwrap();
+    assert!(status.success());
+    let target = root_prefix.join("envs/robot");
+    assert!(target.join("bin/tool").is_file());
+    assert_eq!(
+        fake.calls(),
+        vec![format!("run --prefix {} conda-unpack", target.display())]
+    );
+
+    // A second time, it exists already.
+    let status = fake.csm(&unpack).status().unwrap();
+    assert!(!status.success());
+    let status = fake
+        .csm(&[&unpack[..], &["--force"]].concat())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // Names which would lead out of envs are refused, before anything is
+    // removed.
+    let elsewhere = project.join("elsewhere");
+    std::fs::create_dir_all(&elsewhere).unwrap();
+    for name in ["..", elsewhere.to_str().unwrap()] {
+        let output = fake
+            .csm(&[&unpack[..], &["--force", "--name", name]].concat())
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("not a valid environment name"));
+    }
+    assert!(target.join("bin/tool").is_file());
+    assert!(elsewhere.is_dir());
+}
+
+#[test]
+fn test_micromamba_args_come_first() {
+    let fake = FakeMicromamba::new("micromamba-arg").install();
+
+    let status = fake
+        .csm(&[
+            "--micromamba-arg",
+            "--no-rc",
+            "--micromamba-arg",
+            "-q",
+            "env",
+            "run",
+            "-n",
+            "p",
+            "--",
+            "true",
+        ])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(fake.calls(), vec!["--no-rc -q run --name p true"]);
+}
+
+#[test]
+fn test_env_create_channel_priority() {
+    let fake = FakeMicromamba::new("channel-priority").install();
+    std::fs::write(
+        fake.project_dir().join("robotmk-env.yaml"),
+        "name: prio\nchannels: [conda-forge]\nchannel_priority: strict\ndependencies: [python]\n",
+    )
+    .unwrap();
+    let print_cmd = |extra: &[&str]| {
+        let output = fake
+            .csm(&[&["env", "create", "--print-cmd"], extra].concat())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert_eq!(
+        print_cmd(&[]).trim(),
+        "micromamba create --name prio -c conda-forge python --yes --strict-channel-priority"
+    );
+    assert!(
+        print_cmd(&["--channel-priority", "disabled"])
+            .trim()
+            .ends_with("--yes --no-channel-priority")
+    );
+}
+
+#[test]
+fn test_result_file() {
+    let fake = FakeMicromamba::new("result-file")
+        .respond("run --name proj", "", 4)
+        .install();
+    let result_file = fake.project_dir().join("result.json");
+
+    let status = fake
+        .csm(&[
+            "--result-file",
+            "result.json",
+            "env",
+            "run",
+            "-n",
+            "proj",
+            "--",
+            "true",
+        ])
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(4));
+    assert_eq!(
+        std::fs::read_to_string(&result_file).unwrap(),
+        "{\"success\":false,\"exit_code\":4,\"micromamba_source\":\"path\",\
+         \"message\":\"micromamba failed: exit status: 4\"}\n"
+    );
+}
+
+#[test]
+fn test_robot_run_detach_prints_pid() {
+    let fake = FakeMicromamba::new("detach").install();
+    std::fs::write(fake.project_dir().join("robot.robot"), "").unwrap();
+
+    let output = fake
+        .csm(&["robot", "run", "--name", "proj", "--detach"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let pid: u32 = String::from_utf8(output.stdout)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    assert!(pid > 0);
+}
+
+#[test]
+fn test_env_run_env_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeMicromamba::new("env-file");
+    let cached = fake.cached_micromamba();
+    let seen = fake.project_dir().join("seen-site");
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    std::fs::write(
+        &cached,
+        format!(
+            "#!/bin/sh\n[ \"$1\" = --version ] && exit 0\necho \"$SITE\" > '{}'\n",
+            seen.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(
+        fake.project_dir().join(".env"),
+        "# site\nSITE='berlin dc'\n",
+    )
+    .unwrap();
+
+    let status = fake
+        .csm(&[
+            "env",
+            "run",
+            "-n",
+            "proj",
+            "--env-file",
+            ".env",
+            "--",
+            "true",
+        ])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(std::fs::read_to_string(seen).unwrap(), "berlin dc\n");
+}
+
+#[test]
+fn test_env_update_retries() {
+    let fake = FakeMicromamba::new("retries")
+        .default_exit_code(6)
+        .install();
+
+    let status = fake
+        .csm(&["env", "update", "-n", "flaky", "--retries", "1"])
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(6));
+    assert_eq!(
+        fake.calls(),
+        vec![
+            "update --all --name flaky --yes",
+            "update --all --name flaky --yes"
+        ]
+    );
+}
+
+#[test]
+fn test_env_create_post_create() {
+    let fake = FakeMicromamba::new("post-create")
+        .respond("run", "", 4)
+        .install();
+
+    let status = fake
+        .csm(&[
+            "env",
+            "create",
+            "-n",
+            "hooked",
+            "--force",
+            "--post-create",
+            "pip install -e .",
+        ])
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(4));
+    assert_eq!(
+        fake.calls(),
+        vec![
+            "create --name hooked --yes",
+            "run --name hooked pip install -e ."
+        ]
+    );
+}
+
+#[test]
+fn test_robot_validate() {
+    let fake = FakeMicromamba::new("robot-validate")
+        .respond("env list", ENV_LIST, 0)
+        .install();
+    let project = fake.project_dir();
+    std::fs::write(project.join("robotmk-env.yaml"), "name: existing\n").unwrap();
+    std::fs::write(project.join("robot.robot"), "").unwrap();
+
+    let output = fake
+        .csm(&["robot", "validate", "--path", project.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("[ OK ] Environment: 'existing' exists")
+    );
+
+    std::fs::write(project.join("robotmk-env.yaml"), "name: missing\n").unwrap();
+    let output = fake
+        .csm(&["robot", "validate", "--path", project.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stdout)
+            .contains("[FAIL] Environment: 'missing' does not exist")
+    );
+}
+
+#[test]
+fn test_managed_mambarc() {
+    let fake = FakeMicromamba::new("managed-mambarc").install();
+
+    let status = fake
+        .csm(&["--managed-mambarc", "env", "run", "-n", "p", "--", "true"])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    let mambarc = fake.home_dir().join(".mambarc");
+    assert!(mambarc.is_file());
+    assert_eq!(
+        fake.calls(),
+        vec![format!("--rc-file {} run --name p true", mambarc.display())]
+    );
+}
+
+#[test]
+fn test_managed_mambarc_missing() {
+    let fake = FakeMicromamba::new("managed-mambarc-missing").install();
+
+    let output = fake
+        .csm(&[
+            "--managed-mambarc",
+            "--no-mambarc",
+            "env",
+            "run",
+            "-n",
+            "p",
+            "--",
+            "true",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("does not exist"));
+    assert_eq!(fake.calls(), vec!["run --name p true"]);
+}
+
+#[test]
+fn test_env_prefix() {
+    let fake = FakeMicromamba::new("prefix").install();
+    let prefix = fake.project_dir().join(".env");
+
+    for args in [
+        &["env", "create", "--prefix", ".env"][..],
+        &["env", "run", "--prefix", ".env", "--", "robot", "--version"],
+        &["env", "remove", "--prefix", ".env"],
+    ] {
+        assert!(fake.csm(args).status().unwrap().success());
+    }
+
+    let prefix = prefix.display();
+    assert_eq!(
+        fake.calls(),
+        vec![
+            format!("create --prefix {} --yes", prefix),
+            format!("run --prefix {} robot --version", prefix),
+            format!("env remove --prefix {} --yes", prefix),
+        ]
+    );
+
+    // An environment exists at a prefix if micromamba made it one.
+    std::fs::create_dir_all(fake.project_dir().join(".env/conda-meta")).unwrap();
+    let output = fake
+        .csm(&["env", "create", "--prefix", ".env"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+}
+
+// /proc can't be written to even by root, unlike a directory without write
+// permission.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_read_only_home_falls_back_to_temp_cache() {
+    let fake = FakeMicromamba::new("read-only-home");
+    let tmp = fake.home_dir().join("tmp");
+    std::fs::create_dir_all(&tmp).unwrap();
+
+    let output = fake
+        .csm(&["cache", "path", "--create"])
+        .env("HOME", "/proc")
+        .env("XDG_CACHE_HOME", "/proc/.cache")
+        .env("TMPDIR", &tmp)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let cache_dir = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    assert!(
+        cache_dir.starts_with(tmp.to_str().unwrap()),
+        "{}",
+        cache_dir
+    );
+    assert!(std::path::Path::new(&cache_dir).is_dir());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not writable, using"));
+}
+
+// Probing whether the cache directory is writable would write to it.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_noop_reports_cache_dir_without_probing() {
+    let fake = FakeMicromamba::new("noop-cache-dir");
+
+    let output = fake
+        .csm(&["--noop", "cache", "path"])
+        .env("HOME", "/proc")
+        .env("XDG_CACHE_HOME", "/proc/.cache")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        "/proc/.cache/csm"
+    );
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("is not writable"));
+}
+
+// As above, /proc stands in for a read-only mount.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_read_only_root_prefix() {
+    let fake = FakeMicromamba::new("read-only-root-prefix").install();
+
+    let output = fake
+        .csm(&["--root-prefix", "/proc/csm", "env", "create", "-n", "ro"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("The root prefix /proc/csm is not writable")
+    );
+    assert!(fake.calls().iter().all(|call| !call.starts_with("create")));
+
+    // Only looking doesn't need to write.
+    let output = fake
+        .csm(&["--root-prefix", "/proc/csm", "env", "list"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Nor does packing, as long as conda-pack is already installed.
+    let pack = [
+        "--root-prefix",
+        "/proc/csm",
+        "env",
+        "pack",
+        "-n",
+        "ro",
+        "--output",
+        "ro.tar.gz",
+    ];
+    let output = fake.csm(&pack).output().unwrap();
+    assert!(output.status.success());
+
+    let fake = FakeMicromamba::new("read-only-root-prefix-pack")
+        .respond("run --name ro conda-pack --version", "", 1)
+        .install();
+    let output = fake.csm(&pack).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not writable"));
+    assert!(fake.calls().iter().all(|call| !call.starts_with("install")));
+}
+
+#[test]
+fn test_ephemeral_cache() {
+    let fake = FakeMicromamba::new("ephemeral-cache");
+    let tmp = fake.home_dir().join("tmp");
+    std::fs::create_dir_all(&tmp).unwrap();
+
+    let output = fake
+        .csm(&["--ephemeral-cache", "cache", "path", "--create"])
+        .env("TMPDIR", &tmp)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let cache_dir = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    assert!(
+        cache_dir.starts_with(tmp.join("csm-ephemeral-").to_str().unwrap()),
+        "{}",
+        cache_dir
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&cache_dir));
+    assert!(!std::path::Path::new(&cache_dir).exists());
+}
+
+// Everything csm keeps in the home directory is found through the same $HOME.
+#[test]
+fn test_homedir_consumers_agree() {
+    let fake = FakeMicromamba::new("homedir").install();
+    let home = fake.home_dir();
+    let root_prefix = home.join("root");
+    std::fs::create_dir_all(&home).unwrap();
+    std::fs::write(
+        home.join(".csmrc"),
+        format!("mamba_root_prefix: {}\n", root_prefix.display()),
+    )
+    .unwrap();
+
+    assert!(fake.csm(&["env", "list"]).status().unwrap().success());
+
+    // .csmrc was read from $HOME, and .mambarc created there.
+    assert!(root_prefix.is_dir());
+    assert!(home.join(".mambarc").is_file());
+}
+
+#[test]
+fn test_config_path() {
+    let fake = FakeMicromamba::new("config-path");
+    let csmrc = fake.home_dir().join(".csmrc");
+
+    let output = fake.csm(&["config", "path"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\tdoes not exist\n", csmrc.display())
+    );
+
+    std::fs::create_dir_all(fake.home_dir()).unwrap();
+    std::fs::write(&csmrc, "noop_mode: false\n").unwrap();
+    let output = fake.csm(&["config", "path"]).output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\tloaded\n", csmrc.display())
+    );
+}
+
+#[test]
+fn test_noop_still_checks_existing_env() {
+    let fake = FakeMicromamba::new("noop-existing")
+        .respond("env list", ENV_LIST, 0)
+        .install();
+
+    let output = fake
+        .csm(&["--noop", "env", "create", "--name", "existing"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+
+    let output = fake
+        .csm(&["--noop", "env", "create", "--name", "new"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Would run"));
+    // Only the queries were run.
+    assert_eq!(fake.calls(), vec!["env list --json", "env list --json"]);
+}
+
+#[test]
+fn test_env_remove_pattern() {
+    let fake = FakeMicromamba::new("remove-pattern")
+        .respond(
+            "env list",
+            r#"{"envs": ["/opt/mamba/envs/robot-ci-1", "/opt/mamba/envs/robot-ci-2", "/opt/mamba/envs/robot-prod"]}"#,
+            0,
+        )
+        .respond("env remove --name robot-ci-1", "", 1)
+        .install();
+
+    // Nobody can confirm without a terminal.
+    let output = fake
+        .csm(&["env", "remove", "--pattern", "robot-ci-*"])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Environments matching robot-ci-*:\n  robot-ci-1\n  robot-ci-2\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("without confirmation"));
+    assert_eq!(fake.calls(), vec!["env list --json"]);
+
+    let output = fake
+        .csm(&[
+            "env",
+            "remove",
+            "--pattern",
+            "robot-ci-*",
+            "--yes",
+            "--keep-going",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 of 2 environments failed"));
+    assert_eq!(
+        fake.calls()[2..],
+        [
+            "env remove --name robot-ci-1 --yes",
+            "env remove --name robot-ci-2 --yes"
+        ]
+    );
+}
+
+#[test]
+fn test_unparseable_env_list_is_an_error() {
+    let fake = FakeMicromamba::new("bad-env-list")
+        .respond("env list", "not json: [", 0)
+        .install();
+
+    let output = fake
+        .csm(&["env", "create", "--name", "new"])
+        .output()
+        .unwrap();
+
+    // Without the list, we can't tell whether it exists already.
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("Could not parse the output of micromamba env list")
+    );
+    assert_eq!(fake.calls(), vec!["env list --json"]);
+}
+
+#[test]
+fn test_env_rename() {
+    let fake = FakeMicromamba::new("rename")
+        .respond("env list", ENV_LIST, 0)
+        .respond("create --name broken", "", 1)
+        .install();
+
+    let status = fake
+        .csm(&["env", "rename", "existing", "renamed", "--yes"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        fake.calls()[1..],
+        [
+            "create --name renamed --clone existing --yes",
+            "env remove --name existing --yes"
+        ]
+    );
+
+    // If cloning fails, the original is kept.
+    let status = fake
+        .csm(&["env", "rename", "existing", "broken", "--yes"])
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert_eq!(
+        fake.calls().last().unwrap(),
+        "create --name broken --clone existing --yes"
+    );
+
+    let output = fake
+        .csm(&["env", "rename", "missing", "renamed", "--yes"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'missing' does not exist"));
+}
+
+#[test]
+fn test_no_mambarc_leaves_it_alone() {
+    let fake = FakeMicromamba::new("no-mambarc").install();
+    std::fs::create_dir_all(fake.home_dir()).unwrap();
+    std::fs::write(
+        fake.home_dir().join(".csmrc"),
+        "mambarc_template: /nonexistent/mambarc\n",
+    )
+    .unwrap();
+
+    // The template would have to exist if it were used.
+    assert!(!fake.csm(&["env", "list"]).status().unwrap().success());
+
+    for flag in ["--no-mambarc", "--no-default-mambarc"] {
+        let status = fake.csm(&[flag, "env", "list"]).status().unwrap();
+        assert!(status.success());
+    }
+    assert!(!fake.home_dir().join(".mambarc").exists());
+}
+
+#[test]
+fn test_env_create_from_env() {
+    let fake = FakeMicromamba::new("from-env")
+        .respond("env list", ENV_LIST, 0)
+        .respond(
+            "env export --name existing --from-history",
+            "name: existing\ndependencies:\n- python=3.12\n",
+            0,
+        )
+        .install();
+
+    let status = fake
+        .csm(&[
+            "env",
+            "create",
+            "--name",
+            "new",
+            "--from-env",
+            "existing",
+            "--manifest",
+            "m.json",
+        ])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    let calls = fake.calls();
+    assert_eq!(calls[1], "env export --name existing --from-history");
+    let manifest = std::fs::read_to_string(fake.project_dir().join("m.json")).unwrap();
+    assert!(
+        manifest.contains(
+            r#""spec_file":"existing","spec_sha256":"a554a550588d808840f2f3b3b6eccf211dd8dfc4e3c2227b16a49c31ecc661ba","#
+        ),
+        "{}",
+        manifest
+    );
+    let create = calls.last().unwrap();
+    let exported = create
+        .strip_prefix("env create --file ")
+        .and_then(|rest| rest.strip_suffix(" --name new --yes"))
+        .unwrap_or_else(|| panic!("{}", create));
+    assert!(exported.ends_with(".yaml"));
+    // The private directory it was in is gone too.
+    assert!(!std::path::Path::new(exported).parent().unwrap().exists());
+
+    let output = fake
+        .csm(&["env", "create", "--name", "new", "--from-env", "missing"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'missing' does not exist"));
+}
+
+#[test]
+fn test_env_name_expands_variables() {
+    let fake = FakeMicromamba::new("name-variables").install();
+    std::fs::write(
+        fake.project_dir().join("robotmk-env.yaml"),
+        "name: robot-${CSM_TEST_SITE}-%CSM_TEST_SITE%\n",
+    )
+    .unwrap();
+
+    let status = fake
+        .csm(&["env", "create"])
+        .env("CSM_TEST_SITE", "berlin")
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        fake.calls().last().unwrap(),
+        "create --name robot-berlin-berlin --yes"
+    );
+}
+
+#[test]
+fn test_env_name_despite_invalid_field() {
+    let fake = FakeMicromamba::new("name-invalid-field").install();
+    std::fs::write(
+        fake.project_dir().join("robotmk-env.yaml"),
+        "name: robot-web\nchannel_priority: strikt\n",
+    )
+    .unwrap();
+
+    let status = fake.csm(&["env", "run", "--", "true"]).status().unwrap();
+    assert!(status.success());
+    assert_eq!(fake.calls(), vec!["run --name robot-web true"]);
+
+    // Creating it needs the rest too.
+    let output = fake.csm(&["env", "create"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown variant `strikt`"));
+
+    std::fs::write(
+        fake.project_dir().join("robotmk-env.yaml"),
+        "name: [robot-web\n",
+    )
+    .unwrap();
+    let output = fake.csm(&["env", "run", "--", "true"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Could not parse robotmk-env.yaml"));
+    assert_eq!(fake.calls().len(), 1);
+}
+
+#[test]
+fn test_env_name_despite_invalid_metadata() {
+    let fake = FakeMicromamba::new("name-invalid-metadata").install();
+    let project = fake.project_dir();
+    std::fs::write(
+        project.join("robotmk-env.yaml"),
+        "name: robot-web\nrobotmk: web\n",
+    )
+    .unwrap();
+    std::fs::write(project.join("tests.robot"), "").unwrap();
+
+    for args in [
+        &["env", "remove"][..],
+        &["env", "run", "--", "true"],
+        &["robot", "run", "tests.robot"],
+    ] {
+        let status = fake.csm(args).status().unwrap();
+        assert!(status.success(), "{:?}", args);
+    }
+    assert_eq!(
+        fake.calls(),
+        vec![
+            "env remove --name robot-web --yes",
+            "run --name robot-web true",
+            "run --name robot-web robot ./tests.robot"
+        ]
+    );
+
+    let output = fake.csm(&["robot", "list", "--json"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        r#"[{"path":".","env_name":"robot-web","robotmk":null}]"#
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Could not parse robotmk-env.yaml"));
+}
+
+#[test]
+fn test_env_name_suffix_for_existing_envs() {
+    let fake = FakeMicromamba::new("name-suffix-existing")
+        .respond(
+            "env list",
+            r#"{"envs": ["/opt/mamba/envs/foo-web01", "/opt/mamba/envs/other-web01"]}"#,
+            0,
+        )
+        .respond("list --name", "[]", 0)
+        .respond("env export", "name: foo\n", 0)
+        .install();
+    std::fs::create_dir_all(fake.home_dir()).unwrap();
+    std::fs::write(fake.home_dir().join(".csmrc"), "env_name_suffix: web01\n").unwrap();
+
+    for args in [
+        &["env", "rename", "foo", "bar", "--yes"][..],
+        &["env", "diff", "foo", "other"],
+        &["env", "create", "-n", "copy", "--from-env", "foo"],
+        &["env", "remove", "--pattern", "oth*", "--yes"],
+    ] {
+        let status = fake.csm(args).status().unwrap();
+        assert!(status.success(), "{:?}", args);
+    }
+
+    let calls: Vec<String> = fake
+        .calls()
+        .into_iter()
+        .filter(|call| call != "env list --json")
+        .collect();
+    assert_eq!(
+        calls[..4],
+        [
+            "create --name bar-web01 --clone foo-web01 --yes",
+            "env remove --name foo-web01 --yes",
+            "list --name foo-web01 --json",
+            "list --name other-web01 --json",
+        ]
+    );
+    assert_eq!(calls[4], "env export --name foo-web01 --from-history");
+    assert!(
+        calls[5].ends_with(" --name copy-web01 --yes"),
+        "{}",
+        calls[5]
+    );
+    assert_eq!(calls[6..], ["env remove --name other-web01 --yes"]);
+}
+
+#[test]
+fn test_env_name_suffix() {
+    let fake = FakeMicromamba::new("name-suffix").install();
+    std::fs::create_dir_all(fake.home_dir()).unwrap();
+    std::fs::write(
+        fake.home_dir().join(".csmrc"),
+        "env_name_suffix: ${CSM_TEST_HOST}\n",
+    )
+    .unwrap();
+
+    for args in [
+        &["env", "create", "--name", "robot-foo"][..],
+        &["env", "remove", "--name", "robot-foo"],
+        &["--no-suffix", "env", "remove", "--name", "robot-foo"],
+    ] {
+        let status = fake
+            .csm(args)
+            .env("CSM_TEST_HOST", "web01")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    assert_eq!(
+        fake.calls()[1..],
+        [
+            "create --name robot-foo-web01 --yes",
+            "env remove --name robot-foo-web01 --yes",
+            "env remove --name robot-foo --yes"
+        ]
+    );
+
+    // Without the variable, .csmrc is wrong.
+    let status = fake
+        .csm(&["env", "create", "--name", "robot-foo"])
+        .env_remove("CSM_TEST_HOST")
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+
+    // Said once, not once by each layer.
+    for args in [&["env", "update"][..], &["robot", "run", "missing.robot"]] {
+        let output = fake.csm(args).env_remove("CSM_TEST_HOST").output().unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.matches("which is not set").count(), 1, "{}", stderr);
+    }
+}
+
+#[test]
+fn test_env_install() {
+    let fake = FakeMicromamba::new("install")
+        .respond("env list", ENV_LIST, 0)
+        .install();
+
+    let status = fake
+        .csm(&[
+            "env",
+            "install",
+            "--name",
+            "existing",
+            "-c",
+            "conda-forge",
+            "requests",
+            "python=3.12",
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        fake.calls().last().unwrap(),
+        "install --name existing -c conda-forge requests python=3.12 --yes"
+    );
+
+    let output = fake
+        .csm(&["env", "install", "--name", "missing", "requests"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'missing' does not exist"));
+
+    let output = fake
+        .csm(&["--noop", "env", "install", "--name", "existing", "requests"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Would run"));
+    // Only the query was run.
+    assert_eq!(fake.calls().last().unwrap(), "env list --json");
+}
+
+#[test]
+fn test_invalid_csmrc_exit_code() {
+    let fake = FakeMicromamba::new("invalid-csmrc").install();
+    std::fs::create_dir_all(fake.home_dir()).unwrap();
+    std::fs::write(fake.home_dir().join(".csmrc"), "noop_mode: [oops\n").unwrap();
+
+    let status = fake.csm(&["env", "list"]).status().unwrap();
+    assert_eq!(status.code(), Some(2));
+    assert_eq!(
+        fake.csm(&["config", "check"]).status().unwrap().code(),
+        Some(2)
+    );
+}
+
+#[test]
+fn test_deadline_kills_micromamba() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeMicromamba::new("deadline");
+    let cached = fake.cached_micromamba();
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    std::fs::write(
+        &cached,
+        "#!/bin/sh\n[ \"$1\" = --version ] && exit 0\nexec sleep 30\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let start = std::time::Instant::now();
+    let status = fake
+        .csm(&[
+            "--deadline-secs",
+            "1",
+            "env",
+            "run",
+            "-n",
+            "slow",
+            "--",
+            "true",
+        ])
+        .status()
+        .unwrap();
+
+    assert_eq!(status.code(), Some(124));
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+}
+
+#[test]
+fn test_status_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeMicromamba::new("status-file");
+    let status_file = fake.home_dir().join("status.json");
+    // Show the status file as it is while micromamba runs.
+    let cached = fake.cached_micromamba();
+    std::fs::create_dir_all(cached.parent().unwrap()).unwrap();
+    std::fs::write(
+        &cached,
+        format!(
+            "#!/bin/sh\n[ \"$1\" = --version ] && exit 0\ncat {}\n",
+            status_file.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = fake
+        .csm(&[
+            "--status-file",
+            status_file.to_str().unwrap(),
+            "env",
+            "run",
+            "-n",
+            "robot",
+            "--",
+            "true",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let during = String::from_utf8_lossy(&output.stdout);
+    assert!(during.contains(r#""phase":"running""#), "{}", during);
+    let after = std::fs::read_to_string(&status_file).unwrap();
+    assert!(after.starts_with(r#"{"pid":"#), "{}", after);
+    assert!(after.contains(r#""phase":"done""#), "{}", after);
+}
+
+#[test]
+fn test_env_create_manifest() {
+    let fake = FakeMicromamba::new("manifest").version("2.1.0").install();
+    let project = fake.project_dir();
+    std::fs::write(
+        project.join("robotmk-env.yaml"),
+        "channels: [conda-forge]\ndependencies: [python]\n",
+    )
+    .unwrap();
+
+    let status = fake
+        .csm(&[
+            "env",
+            "create",
+            "-n",
+            "audited",
+            "--force",
+            "--manifest",
+            "m.json",
+        ])
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    let manifest = std::fs::read_to_string(project.join("m.json")).unwrap();
+    assert!(
+        manifest.starts_with(
+            r#"{"env_name":"audited","prefix":null,"spec_file":"./robotmk-env.yaml","spec_sha256":"26da80398ee7dfc260a7af5ee8508f78c1b1cb9cf4af6f9f06dbcfe99725102b","#
+        ),
+        "{}",
+        manifest
+    );
+    assert!(manifest.contains(r#""channels":["conda-forge"],"micromamba_version":"2.1.0","#));
+}
+
+#[test]
+fn test_doctor_noop_leaves_cache_dir_alone() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let fake = FakeMicromamba::new("doctor-noop").install();
+    let cache_dir = fake.home_dir().join(".cache/csm");
+
+    let output = fake.csm(&["--noop", "doctor"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("does not exist yet, not creating it"),
+        "{}",
+        stdout
+    );
+    assert!(!cache_dir.exists());
+
+    std::fs::create_dir_all(&cache_dir).unwrap();
+    std::fs::set_permissions(&cache_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let output = fake.csm(&["--noop", "doctor"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("exists (mode 755"), "{}", stdout);
+    assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 0);
+
+    // Without --noop, it is really checked.
+    let output = fake.csm(&["doctor"]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("is writable and allows executing"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_robot_new_does_not_follow_symlinks() {
+    let fake = FakeMicromamba::new("robot-new-symlink");
+    let project = fake.project_dir();
+    let outside = fake.home_dir().join("outside");
+    std::fs::create_dir_all(&outside).unwrap();
+    std::os::unix::fs::symlink(&outside, project.join("escape")).unwrap();
+
+    let output = fake.csm(&["robot", "new", "escape/web"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("outside of the current directory"));
+    assert!(!outside.join("web").exists());
+
+    // Nor does it write through a symlinked file, even one which points
+    // nowhere yet.
+    std::fs::create_dir(project.join("robot")).unwrap();
+    std::os::unix::fs::symlink(
+        outside.join("planted"),
+        project.join("robot").join("robot.robot"),
+    )
+    .unwrap();
+    let output = fake.csm(&["robot", "new", "robot"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is a symlink"));
+    assert!(!outside.join("planted").exists());
+    assert!(!project.join("robot").join("robotmk-env.yaml").exists());
+}